@@ -1,5 +1,8 @@
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
-use sdr_heatmap::{open_file, preprocess, preprocess_iter, process, process_iter, Palette};
+use sdr_heatmap::{
+    open_file, preprocess, preprocess_fast, preprocess_iter, preprocess_slice, process,
+    process_fast, process_iter, process_slice, Palette, ReadOptions,
+};
 use std::{
     fs::read_dir,
     io::{Cursor, Read},
@@ -49,7 +52,7 @@ fn get_test_files() -> std::vec::Vec<std::path::PathBuf> {
 fn preprocess_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("preprocess implementations");
     for file in get_test_files().iter() {
-        let size = get_file_size(&file);
+        let size = get_file_size(file);
         group.throughput(Throughput::Bytes(size));
         group.bench_with_input(
             BenchmarkId::new("basic", file.display()),
@@ -58,7 +61,7 @@ fn preprocess_bench(c: &mut Criterion) {
                 b.iter_with_large_setup(
                     || read_file_to_memory(file),
                     |data| {
-                        let summary = preprocess(data);
+                        let summary = preprocess(data).unwrap();
                         black_box(summary);
                     },
                 )
@@ -71,7 +74,34 @@ fn preprocess_bench(c: &mut Criterion) {
                 b.iter_with_large_setup(
                     || read_file_to_memory(file),
                     |data| {
-                        let summary = preprocess_iter(data);
+                        let summary = preprocess_iter(data).unwrap();
+                        black_box(summary);
+                    },
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("fast", file.display()),
+            &file,
+            |b, file| {
+                b.iter_with_large_setup(
+                    || read_file_to_memory(file),
+                    |data| {
+                        let summary = preprocess_fast(data, &ReadOptions::default()).unwrap();
+                        black_box(summary);
+                    },
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("slice", file.display()),
+            &file,
+            |b, file| {
+                b.iter_with_large_setup(
+                    || read_file_to_memory(file),
+                    |data| {
+                        let summary =
+                            preprocess_slice(data.get_ref(), &ReadOptions::default()).unwrap();
                         black_box(summary);
                     },
                 )
@@ -85,7 +115,7 @@ fn preprocess_bench(c: &mut Criterion) {
 fn process_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("process implementations");
     for file in get_test_files().iter() {
-        let size = get_file_size(&file);
+        let size = get_file_size(file);
         group.throughput(Throughput::Bytes(size));
         group.bench_with_input(
             BenchmarkId::new("basic", file.display()),
@@ -113,6 +143,46 @@ fn process_bench(c: &mut Criterion) {
                 )
             },
         );
+        group.bench_with_input(
+            BenchmarkId::new("fast", file.display()),
+            &file,
+            |b, file| {
+                b.iter_with_large_setup(
+                    || read_file_to_memory(file),
+                    |data| {
+                        let summary = process_fast(
+                            data,
+                            -1000.0,
+                            1000.0,
+                            Palette::Default,
+                            &ReadOptions::default(),
+                        )
+                        .unwrap();
+                        black_box(summary);
+                    },
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("slice", file.display()),
+            &file,
+            |b, file| {
+                b.iter_with_large_setup(
+                    || read_file_to_memory(file),
+                    |data| {
+                        let summary = process_slice(
+                            data.get_ref(),
+                            -1000.0,
+                            1000.0,
+                            Palette::Default,
+                            &ReadOptions::default(),
+                        )
+                        .unwrap();
+                        black_box(summary);
+                    },
+                )
+            },
+        );
     }
 
     group.finish();
@@ -0,0 +1,165 @@
+//! Detecting compressed inputs by their magic bytes, so renamed files are still decompressed.
+
+use crate::error::{DecompressionError, Result};
+use std::io::{self, BufRead, Read};
+
+/// File name endings of captures the recursive mode picks up
+pub const EXTENSIONS: [&str; 5] = [".csv", ".csv.gz", ".csv.zst", ".csv.xz", ".csv.bz2"];
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Detects the compression from the first bytes of a file
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if header.starts_with(BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Detects the compression of a buffered reader without consuming anything
+    pub fn sniff<R: BufRead>(reader: &mut R) -> Result<Self> {
+        Ok(Self::detect(reader.fill_buf()?))
+    }
+
+    /// Wraps `reader` in a decoder for this compression
+    pub fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(Decoder(flate2::bufread::MultiGzDecoder::new(reader))),
+            Compression::Zstd => Box::new(Decoder(
+                zstd::stream::read::Decoder::with_buffer(reader).map_err(decompression)?,
+            )),
+            Compression::Xz => {
+                Box::new(Decoder(xz2::bufread::XzDecoder::new_multi_decoder(reader)))
+            }
+            Compression::Bzip2 => Box::new(Decoder(bzip2::bufread::MultiBzDecoder::new(reader))),
+        })
+    }
+}
+
+fn decompression(err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), DecompressionError(err))
+}
+
+/// Marks errors of a decoder, so they're reported as [`crate::Error::Decompression`]
+struct Decoder<R>(R);
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::UnexpectedEof => decompression(err),
+            _ => err,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::io::Write;
+
+    const DATA: &[u8] = b"2019-08-17, 22:37:25, 0, 4, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0\n";
+
+    fn compress(compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::None => DATA.to_vec(),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(DATA).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(DATA, 1).unwrap(),
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+                encoder.write_all(DATA).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Bzip2 => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                encoder.write_all(DATA).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for &compression in &[
+            Compression::None,
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Xz,
+            Compression::Bzip2,
+        ] {
+            let compressed = compress(compression);
+            let mut reader = &compressed[..];
+            assert_eq!(Compression::sniff(&mut reader).unwrap(), compression);
+            let mut data = Vec::new();
+            compression
+                .decoder(reader)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, DATA, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn concatenated() {
+        // Such as a capture appended to with `gzip >>`, which has a member for every part
+        let mut compressed = compress(Compression::Gzip);
+        compressed.extend(compress(Compression::Gzip));
+        let mut data = Vec::new();
+        Compression::Gzip
+            .decoder(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [DATA, DATA].concat());
+    }
+
+    #[test]
+    fn corrupt() {
+        for &compression in &[
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Xz,
+            Compression::Bzip2,
+        ] {
+            let compressed = compress(compression);
+            let mut data = Vec::new();
+            let err: Error = compression
+                .decoder(&compressed[..compressed.len() / 2])
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap_err()
+                .into();
+            assert!(matches!(err, Error::Decompression(_)), "{:?}", compression);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Rendering many captures, such as rtl_power's hourly files, into one continuous image.
+
+use crate::error::{Error, Result};
+use crate::lenient::{ReadOptions, Report};
+use crate::sigmf::Extent;
+use crate::sweep::same_grid;
+use crate::timezone::Clock;
+use crate::{
+    arrange, detect_file_format, formats, normalize, open_file, paint, read_head, write_output,
+    Output, RenderOptions, Waterfall,
+};
+use chrono::{DateTime, Utc};
+use log::*;
+use rayon::prelude::*;
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// Color of the row inserted before every file but the first, if boundaries are marked
+const BOUNDARY: [u8; 3] = [255, 255, 255];
+
+/// What the first pass found out about one of the files
+struct Part {
+    path: PathBuf,
+    options: ReadOptions,
+    /// Its first sweep's grid is what all files have to share
+    extent: Extent,
+}
+
+pub(crate) fn in_file(path: &Path) -> impl FnOnce(Error) -> Error + '_ {
+    move |source| Error::InFile {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    }
+}
+
+fn scan(path: &Path, options: &ReadOptions) -> Result<Part> {
+    info!("Loading: {}", path.display());
+    let options = detect_file_format(path, options)?;
+    let mut report = Report::default();
+    let mut extent = Extent::default();
+    let mut clock = Clock::new(options.timezone());
+    let reader = BufReader::new(open_file(path)?);
+    formats::for_each_sweep(reader, &options, &mut report, |sweep| {
+        extent.update(&sweep, &mut clock);
+        Ok(())
+    })?;
+    Ok(Part {
+        path: path.to_path_buf(),
+        options,
+        extent,
+    })
+}
+
+/// When the earliest of the files starts, to count time offsets from
+fn first_timestamp(paths: &[PathBuf], options: &ReadOptions) -> Result<Option<DateTime<Utc>>> {
+    let firsts = paths
+        .par_iter()
+        .map(|path| {
+            let head = read_head(path).map_err(in_file(path))?;
+            let options = options.for_input(&head);
+            Ok(formats::first_timestamp(&head, &options))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(firsts.into_iter().flatten().min())
+}
+
+/// Renders `paths` into a single image, ordered by their first sweep.
+/// All files have to cover the same frequencies with the same bins.
+pub fn render_many(
+    paths: &[PathBuf],
+    output: &Output,
+    render_options: &RenderOptions,
+    options: &ReadOptions,
+) -> Result<()> {
+    let options = &options.starting_at(first_timestamp(paths, options)?);
+    //Preprocess
+    let mut parts = paths
+        .par_iter()
+        .map(|path| scan(path, options).map_err(in_file(path)))
+        .collect::<Result<Vec<_>>>()?;
+    parts.retain(|part| {
+        if part.extent.sweeps == 0 {
+            warn!("Skipping '{}', it contains no sweeps", part.path.display());
+        }
+        part.extent.sweeps > 0
+    });
+    parts.sort_by_key(|part| part.extent.first);
+    for pair in parts.windows(2) {
+        let (previous, part) = (&pair[0], &pair[1]);
+        if !same_grid(&parts[0].extent.grid, &part.extent.grid) {
+            return Err(Error::Concat {
+                path: part.path.clone(),
+                message: format!(
+                    "Its frequencies differ from file '{}'",
+                    parts[0].path.display()
+                ),
+            });
+        }
+        if part.extent.first <= previous.extent.last {
+            warn!(
+                "'{}' starts before '{}' ends",
+                part.path.display(),
+                previous.path.display()
+            );
+        }
+    }
+    //Process
+    let waterfalls = parts
+        .par_iter()
+        .map(|part| {
+            let file = open_file(&part.path)?;
+            Waterfall::read(file, &part.options).map_err(in_file(&part.path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut waterfall = Waterfall::default();
+    let mut report = Report::default();
+    for (part, part_report) in waterfalls {
+        if waterfall.height() > 0 && part.height() > 0 {
+            waterfall.boundaries.push(waterfall.height());
+        }
+        waterfall.append(part);
+        report = Report::merge(report, part_report);
+    }
+    if options.crops() && waterfall.values.is_empty() {
+        return Err(Error::EmptyRange);
+    }
+    let files = parts.len();
+    let extent = parts
+        .into_iter()
+        .fold(Extent::default(), |a, part| Extent::merge(a, part.extent));
+    let waterfall = normalize(waterfall, &extent.grid, render_options, options)?;
+    let (waterfall, summary) = arrange(waterfall, render_options);
+    let datawidth = waterfall.width;
+    let mut dataheight = waterfall.height();
+    let mut img = paint(&waterfall, &summary, render_options);
+    if render_options.mark_boundaries {
+        img = insert_boundaries(img, datawidth, &waterfall.boundaries);
+        dataheight += waterfall.boundaries.len();
+    }
+    info!("Img data {}x{} from {} files", datawidth, dataheight, files);
+
+    write_output(
+        datawidth,
+        dataheight,
+        img,
+        output,
+        render_options,
+        Some(extent),
+    )?;
+    report.log();
+    Ok(())
+}
+
+/// Inserts a row of [`BOUNDARY`] before each of `boundaries`, so marking them doesn't hide any sweeps
+fn insert_boundaries(img: Vec<u8>, width: usize, boundaries: &[usize]) -> Vec<u8> {
+    let row = width * 3;
+    let mut marked = Vec::with_capacity(img.len() + boundaries.len() * row);
+    let mut start = 0;
+    for &boundary in boundaries {
+        let end = (boundary * row).min(img.len());
+        marked.extend_from_slice(&img[start..end]);
+        marked.extend(BOUNDARY.iter().cycle().take(row));
+        start = end;
+    }
+    marked.extend_from_slice(&img[start..]);
+    marked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::capture;
+    use std::fs;
+
+    #[test]
+    fn concatenates_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let sweeps = vec![vec![-10.0, -20.0, -30.0]; 3];
+        let later = capture(dir.path(), "later.csv", "2019-08-17, 22:38", 1, &sweeps);
+        let earlier = capture(dir.path(), "earlier.csv", "2019-08-17, 22:37", 1, &sweeps);
+        let other = capture(dir.path(), "other.csv", "2019-08-17, 22:39", 2, &sweeps);
+        let output = dir.path().join("all.png");
+        let render_options = RenderOptions {
+            mark_boundaries: true,
+            metadata: Some(Default::default()),
+            ..RenderOptions::default()
+        };
+        let options = ReadOptions::default();
+
+        render_many(
+            &[later.clone(), earlier],
+            &Output::File(output.clone()),
+            &render_options,
+            &options,
+        )
+        .unwrap();
+        let image = image::open(&output).unwrap().to_rgb8();
+        // The second file starts 58 seconds after the first one ends, a sweep every second,
+        // and a row marks where it starts
+        assert_eq!(image.dimensions(), (2, 26 + 6 + 57 + 1));
+        assert_eq!(image.get_pixel(0, 26 + 3).0, [0, 0, 50]);
+        assert_eq!(image.get_pixel(0, 26 + 3 + 57).0, BOUNDARY);
+        assert_eq!(image.get_pixel(0, 26 + 4 + 57), image.get_pixel(0, 26));
+        assert_eq!(image.get_pixel(0, 26 + 6 + 57), image.get_pixel(0, 26 + 2));
+        let meta = fs::read_to_string(dir.path().join("all.sigmf-meta")).unwrap();
+        assert!(meta.contains("2019-08-17T22:37:00Z"));
+        // The sweeps of both files, not the rows of the image
+        assert!(meta.contains("\"core:sample_count\": 6,"));
+
+        let err = render_many(
+            &[later, other],
+            &Output::File(output),
+            &render_options,
+            &options,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Concat { .. }));
+    }
+}
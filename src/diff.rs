@@ -0,0 +1,385 @@
+//! Difference between two captures of the same band, such as before and after installing a filter.
+
+use crate::concat::in_file;
+use crate::error::{Error, Result};
+use crate::lenient::{ReadOptions, Report};
+use crate::sigmf::Extent;
+use crate::sweep::Sweep;
+use crate::timezone::Clock;
+use crate::{
+    arrange, detect_file_format, formats, open_file, paint, write_output, Aggregation, Output,
+    RenderOptions, Summary, Waterfall,
+};
+use log::*;
+use std::{io::BufReader, path::Path};
+
+/// Reads which frequencies a capture covers, and its first sweep to compare bins with
+fn scan(path: &Path, options: &ReadOptions) -> Result<(Extent, Option<Sweep>)> {
+    info!("Loading: {}", path.display());
+    let options = detect_file_format(path, options)?;
+    let mut extent = Extent::default();
+    let mut first = None;
+    let mut clock = Clock::new(options.timezone());
+    let reader = BufReader::new(open_file(path)?);
+    formats::for_each_sweep(reader, &options, &mut Report::default(), |sweep| {
+        extent.update(&sweep, &mut clock);
+        first.get_or_insert(sweep);
+        Ok(())
+    })?;
+    Ok((extent, first))
+}
+
+/// Frequency and width of each bin of `sweep` between `low` and `high`, in Hz
+fn bins(sweep: &Sweep, low: f64, high: f64) -> Vec<(f64, f64)> {
+    sweep
+        .hops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, hop)| {
+            (0..sweep.hop_values(i).len()).map(move |bin| {
+                (
+                    hop.freq_low as f64 + bin as f64 * hop.freq_step,
+                    hop.freq_step,
+                )
+            })
+        })
+        .filter(|&(freq, step)| freq + step > low && freq < high)
+        .collect()
+}
+
+/// Whether two sweeps have bins of the same size at the same frequencies between `low` and
+/// `high`, within a hundredth of a bin
+fn same_bins(a: &Sweep, b: &Sweep, low: f64, high: f64) -> bool {
+    let (a, b) = (bins(a, low, high), bins(b, low, high));
+    a.len() == b.len()
+        && a.iter().zip(&b).all(|(&(a, step), &(b, other_step))| {
+            let tolerance = step.abs().max(other_step.abs()) / 100.0;
+            (a - b).abs() <= tolerance && (step - other_step).abs() <= tolerance
+        })
+}
+
+fn read(path: &Path, options: &ReadOptions) -> Result<(Waterfall, Report)> {
+    let options = detect_file_format(path, options)?;
+    Waterfall::read(open_file(path)?, &options)
+}
+
+/// Time of each row since the first one, in milliseconds
+fn offsets(waterfall: &Waterfall) -> Vec<Option<i64>> {
+    let first = waterfall.times.iter().flatten().next().copied();
+    waterfall
+        .times
+        .iter()
+        .map(|time| Some((*time)? - first?).map(|offset| offset.num_milliseconds()))
+        .collect()
+}
+
+/// Subtracts `before` from `after`, matching rows at the same time since each capture started.
+/// A row of `before` is matched to the closest row of `after`, if it is within half a sweep,
+/// or to the row with the same index if the captures have no timestamps.
+fn subtract(before: &Waterfall, after: &Waterfall) -> Waterfall {
+    let (offsets, other) = (offsets(before), offsets(after));
+    let mut rows = other
+        .iter()
+        .enumerate()
+        .filter_map(|(row, offset)| Some(((*offset)?, row)))
+        .collect::<Vec<_>>();
+    rows.sort_unstable();
+    let matches = if offsets.iter().any(Option::is_some) && !rows.is_empty() {
+        let tolerance = [before.interval(), after.interval()]
+            .iter()
+            .flatten()
+            .map(|interval| interval.num_milliseconds() / 2)
+            .max()
+            .unwrap_or(0);
+        offsets
+            .iter()
+            .map(|offset| {
+                let offset = (*offset)?;
+                let next = rows.partition_point(|&(other, _)| other < offset);
+                rows[next.saturating_sub(1)..(next + 1).min(rows.len())]
+                    .iter()
+                    .min_by_key(|&&(other, _)| (other - offset).abs())
+                    .filter(|&&(other, _)| (other - offset).abs() <= tolerance)
+                    .map(|&(_, row)| row)
+            })
+            .collect::<Vec<_>>()
+    } else {
+        (0..before.height())
+            .map(|row| (row < after.height()).then_some(row))
+            .collect()
+    };
+    // Past the end of the shorter capture, there is nothing to compare
+    let height = matches
+        .iter()
+        .rposition(Option::is_some)
+        .map_or(0, |row| row + 1);
+    let values = matches[..height]
+        .iter()
+        .enumerate()
+        .flat_map(|(row, other)| {
+            let before = before.row(row);
+            let after = other.map(|other| after.row(other));
+            (0..before.len()).map(move |bin| match after {
+                Some(after) => after[bin] - before[bin],
+                None => f32::NAN,
+            })
+        })
+        .collect();
+    Waterfall {
+        width: before.width,
+        values,
+        times: before.times[..height].to_vec(),
+        boundaries: Vec::new(),
+    }
+}
+
+/// Renders how much stronger each bin of `after` is than the same bin of `before`, in dB.
+/// Only frequencies both captures cover are compared, and sweeps are matched by the time since
+/// each capture started. Both have to have bins of the same size at the same frequencies.
+/// [`Palette::Diverging`](crate::Palette::Diverging) shows no change in the middle of the palette.
+/// The difference is already relative, so a baseline, sweep levels, boundaries and metadata
+/// don't apply, and are ignored with a warning.
+pub fn render_diff(
+    before: &Path,
+    after: &Path,
+    output: &Output,
+    render_options: &RenderOptions,
+    options: &ReadOptions,
+) -> Result<()> {
+    for (name, set) in [
+        ("a baseline", render_options.baseline.is_some()),
+        ("sweep levels", render_options.sweep_level.is_some()),
+        ("file boundaries", render_options.mark_boundaries),
+        ("metadata", render_options.metadata.is_some()),
+    ] {
+        if set {
+            warn!(
+                "Ignoring {}, because the image shows a difference of two captures",
+                name
+            );
+        }
+    }
+    let (extents, others) = rayon::join(
+        || scan(before, options).map_err(in_file(before)),
+        || scan(after, options).map_err(in_file(after)),
+    );
+    let ((extent, first), (other, other_first)) = (extents?, others?);
+    let no_common = |message: &str| Error::Diff {
+        path: after.to_path_buf(),
+        message: message.to_string(),
+    };
+    let (low, high) = match (
+        extent.freq_low,
+        extent.freq_high,
+        other.freq_low,
+        other.freq_high,
+    ) {
+        (Some(low), Some(high), Some(other_low), Some(other_high)) => {
+            (low.max(other_low) as f64, high.min(other_high) as f64)
+        }
+        _ => return Err(no_common("One of the captures contains no sweeps")),
+    };
+    let low = options.freq_min.map_or(low, |min| min.max(low));
+    let high = options.freq_max.map_or(high, |max| max.min(high));
+    if low >= high {
+        return Err(no_common(
+            "It has no frequencies in common with the other capture",
+        ));
+    }
+    if let (Some(first), Some(other_first)) = (&first, &other_first) {
+        if !same_bins(first, other_first, low, high) {
+            return Err(no_common(
+                "Its bins aren't at the same frequencies as those of the other capture",
+            ));
+        }
+    }
+    info!("Comparing {} Hz to {} Hz", low, high);
+    let options = ReadOptions {
+        freq_min: Some(low),
+        freq_max: Some(high),
+        ..options.clone()
+    };
+
+    let (waterfalls, others) = rayon::join(
+        || read(before, &options).map_err(in_file(before)),
+        || read(after, &options).map_err(in_file(after)),
+    );
+    let ((waterfall, report), (other, other_report)) = (waterfalls?, others?);
+    if waterfall.values.is_empty() || other.values.is_empty() {
+        return Err(Error::EmptyRange);
+    }
+    if waterfall.width != other.width {
+        return Err(no_common(&format!(
+            "It has {} bins from {} Hz to {} Hz, but the other capture has {}",
+            other.width, low, high, waterfall.width
+        )));
+    }
+    let difference = subtract(&waterfall, &other);
+    // Differences in dB aren't power, so combining them takes their plain average
+    let aggregation = match render_options.aggregation {
+        Aggregation::Mean => Aggregation::Average,
+        aggregation => aggregation,
+    };
+    let (difference, summary) = arrange(
+        difference,
+        &RenderOptions {
+            aggregation,
+            ..render_options.clone()
+        },
+    );
+    // Centered at zero, so no change is in the middle of the palette
+    let limit = summary.min.abs().max(summary.max.abs());
+    let limit = if limit.is_finite() && limit > 0.0 {
+        limit
+    } else {
+        1.0
+    };
+    info!("Differences from {} to {} dB", summary.min, summary.max);
+    let summary = Summary {
+        min: -limit,
+        max: limit,
+        ..summary
+    };
+    let img = paint(&difference, &summary, render_options);
+    write_output(
+        difference.width,
+        difference.height(),
+        img,
+        output,
+        &RenderOptions {
+            metadata: None,
+            ..render_options.clone()
+        },
+        None,
+    )?;
+    Report::merge(report, other_report).log();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::capture;
+    use std::fs;
+
+    /// Four sweeps, with the middle bin rising by 1 dB a sweep from `offset` - 30 dB
+    fn sweeps(offset: f32) -> Vec<Vec<f32>> {
+        (0..4)
+            .map(|second| vec![-10.0, -30.0 + offset + second as f32, -20.0])
+            .collect()
+    }
+
+    #[test]
+    fn compares_captures() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = capture(
+            dir.path(),
+            "before.csv",
+            "2019-08-17, 22:37",
+            1,
+            &sweeps(0.0),
+        );
+        let after = capture(
+            dir.path(),
+            "after.csv",
+            "2019-08-24, 22:37",
+            1,
+            &sweeps(4.0),
+        );
+        let output = dir.path().join("diff.png");
+
+        render_diff(
+            &before,
+            &after,
+            &Output::File(output.clone()),
+            &RenderOptions {
+                palette: crate::Palette::Diverging,
+                ..RenderOptions::default()
+            },
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        let image = image::open(&output).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (2, 26 + 4));
+        assert_eq!(image.get_pixel(0, 26).0, [255, 255, 255]);
+        assert_eq!(image.get_pixel(1, 26).0, [255, 0, 0]);
+
+        // The middle bin is 8 dB stronger and weaker in turns, which averages to no change,
+        // while the first bin is 8 dB stronger throughout
+        let alternating = sweeps(0.0)
+            .into_iter()
+            .enumerate()
+            .map(|(second, sweep)| {
+                let change = if second % 2 == 0 { 8.0 } else { -8.0 };
+                vec![sweep[0] + 8.0, sweep[1] + change, sweep[2]]
+            })
+            .collect::<Vec<_>>();
+        let alternating = capture(
+            dir.path(),
+            "alternating.csv",
+            "2019-08-24, 22:37",
+            1,
+            &alternating,
+        );
+        render_diff(
+            &before,
+            &alternating,
+            &Output::File(output.clone()),
+            &RenderOptions {
+                palette: crate::Palette::Diverging,
+                height: Some(1),
+                ..RenderOptions::default()
+            },
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        let image = image::open(&output).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (2, 26 + 1));
+        assert_eq!(image.get_pixel(0, 26).0, [255, 0, 0]);
+        assert_eq!(image.get_pixel(1, 26).0, [255, 255, 255]);
+
+        let other = capture(
+            dir.path(),
+            "other.csv",
+            "2019-08-24, 22:37",
+            2,
+            &sweeps(0.0),
+        );
+        let err = render_diff(
+            &before,
+            &other,
+            &Output::File(output),
+            &RenderOptions::default(),
+            &ReadOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Diff { .. }));
+    }
+
+    #[test]
+    fn rejects_offset_bins() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = dir.path().join("low.csv");
+        fs::write(
+            &before,
+            "2019-08-17, 22:37:00, 0, 40, 10, 1, -10, -20, -30, -40\n",
+        )
+        .unwrap();
+        let after = dir.path().join("high.csv");
+        fs::write(
+            &after,
+            "2019-08-24, 22:37:00, 5, 45, 10, 1, -10, -20, -30, -40\n",
+        )
+        .unwrap();
+
+        let err = render_diff(
+            &before,
+            &after,
+            &Output::File(dir.path().join("offset.png")),
+            &RenderOptions::default(),
+            &ReadOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Diff { .. }));
+    }
+}
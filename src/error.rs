@@ -0,0 +1,231 @@
+//! Errors returned by the library, so consumers can tell them apart without matching on messages.
+
+use crate::parser::Position;
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The input file couldn't be opened
+    #[error("Couldn't open file '{}'", .path.display())]
+    Open { path: PathBuf, source: io::Error },
+    /// The output file couldn't be created
+    #[error("Couldn't create file '{}'", .path.display())]
+    Create { path: PathBuf, source: io::Error },
+    /// Reading the input failed
+    #[error("Couldn't read input")]
+    Io(#[source] io::Error),
+    /// The input is compressed, but the compressed data is corrupt
+    #[error("Couldn't decompress input")]
+    Decompression(#[source] io::Error),
+    /// A line doesn't have the expected structure. Columns start at 1.
+    #[error("Invalid record{}{}: {message}", position_suffix(.position), column_suffix(.column))]
+    Csv {
+        position: Option<Position>,
+        column: Option<usize>,
+        message: String,
+    },
+    /// A column that should contain a number couldn't be parsed. Columns start at 1.
+    #[error("Invalid number '{value}'{}, column {column}", position_suffix(.position))]
+    Number {
+        position: Option<Position>,
+        column: usize,
+        value: String,
+    },
+    /// The frequencies or number of values of a line don't fit with each other or the rest of the file
+    #[error("Inconsistent sweep geometry{}: {message}", position_suffix(.position))]
+    Geometry {
+        position: Option<Position>,
+        message: String,
+    },
+    /// A record of a binary capture is broken
+    #[error("Invalid binary record at byte {offset}: {message}")]
+    Binary { offset: u64, message: String },
+    /// Reading one of several inputs failed
+    #[error("Error in file '{}'", .path.display())]
+    InFile {
+        path: PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
+    /// A file can't be rendered into the same image as the others
+    #[error("Can't concatenate file '{}': {message}", .path.display())]
+    Concat { path: PathBuf, message: String },
+    /// Two captures can't be compared
+    #[error("Can't compare with file '{}': {message}", .path.display())]
+    Diff { path: PathBuf, message: String },
+    /// The selected frequencies or times contain no values
+    #[error("Nothing to render, no sweeps are in the selected range")]
+    EmptyRange,
+    /// A reference capture doesn't cover the same bins as the capture
+    #[error("Can't use reference capture '{}': {message}", .path.display())]
+    Reference { path: PathBuf, message: String },
+    /// The SigMF metadata couldn't be written
+    #[error("Couldn't write metadata")]
+    Metadata(#[source] serde_json::Error),
+    /// The image couldn't be encoded
+    #[error("Couldn't encode image")]
+    Image(#[from] image::ImageError),
+}
+
+fn position_suffix(position: &Option<Position>) -> String {
+    match position {
+        Some(position) => format!(" on {}", position),
+        None => String::new(),
+    }
+}
+
+fn column_suffix(column: &Option<usize>) -> String {
+    match column {
+        Some(column) => format!(", column {}", column),
+        None => String::new(),
+    }
+}
+
+impl Error {
+    pub(crate) fn csv(column: Option<usize>, message: impl Into<String>) -> Self {
+        Error::Csv {
+            position: None,
+            column,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn number(column: usize, value: impl Into<String>) -> Self {
+        Error::Number {
+            position: None,
+            column,
+            value: value.into(),
+        }
+    }
+
+    pub(crate) fn geometry(message: impl Into<String>) -> Self {
+        Error::Geometry {
+            position: None,
+            message: message.into(),
+        }
+    }
+
+    /// Position of the line that caused the error, if it's about a specific line
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::Csv { position, .. }
+            | Error::Number { position, .. }
+            | Error::Geometry { position, .. } => *position,
+            _ => None,
+        }
+    }
+
+    /// Sets the position of errors about a line, which are created before the position is known
+    pub(crate) fn at(mut self, at: Position) -> Self {
+        match &mut self {
+            Error::Csv { position, .. }
+            | Error::Number { position, .. }
+            | Error::Geometry { position, .. } => *position = Some(at),
+            _ => {}
+        }
+        self
+    }
+}
+
+/// Wraps errors of a decoder, so they can be told apart from other I/O errors once they're read
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub(crate) struct DecompressionError(pub io::Error);
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if matches!(err.get_ref(), Some(inner) if inner.is::<DecompressionError>()) {
+            match err
+                .into_inner()
+                .map(|inner| inner.downcast::<DecompressionError>())
+            {
+                Some(Ok(inner)) => Error::Decompression(inner.0),
+                _ => unreachable!("checked above"),
+            }
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        let position = err.position().map(Position::from);
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => err.into(),
+            csv::ErrorKind::Utf8 { err, .. } => Error::Csv {
+                position,
+                column: Some(err.field() + 1),
+                message: "Column is not valid UTF-8".to_string(),
+            },
+            csv::ErrorKind::UnequalLengths {
+                expected_len, len, ..
+            } => Error::Csv {
+                position,
+                column: None,
+                message: format!("Expected {} columns, found {}", expected_len, len),
+            },
+            kind => Error::Csv {
+                position,
+                column: None,
+                message: format!("{:?}", kind),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn decompression_errors() {
+        let mut data = Vec::new();
+        let err: Error = flate2::read::GzDecoder::new(&b"not gzip"[..])
+            .read_to_end(&mut data)
+            .map_err(|e| io::Error::new(e.kind(), DecompressionError(e)))
+            .unwrap_err()
+            .into();
+        assert!(matches!(err, Error::Decompression(_)));
+        let err: Error = io::Error::new(io::ErrorKind::PermissionDenied, "disk").into();
+        assert!(matches!(err, Error::Io(_)));
+    }
+
+    #[test]
+    fn csv_errors() {
+        let err = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(&b"a,b\na,b,c\n"[..])
+            .into_records()
+            .find_map(|r| r.err())
+            .unwrap();
+        match err.into() {
+            Error::Csv {
+                position, column, ..
+            } => {
+                assert_eq!(position, Some(Position { line: 2, byte: 4 }));
+                assert_eq!(column, None);
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn display() {
+        let err = Error::number(8, "abc").at(Position { line: 2, byte: 43 });
+        assert_eq!(
+            err.to_string(),
+            "Invalid number 'abc' on line 2, byte 43, column 8"
+        );
+        let err = Error::csv(None, "Expected 7 columns, found 3");
+        assert_eq!(
+            err.to_string(),
+            "Invalid record: Expected 7 columns, found 3"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! rtl_power_fftw's text format: a frequency and power pair on every line, hops separated by blank lines
+//! and metadata in `#` comments.
+
+use crate::error::{Error, Result};
+use crate::lenient::{Problem, ReadOptions, Report};
+use crate::parser::{self, trim};
+use crate::sweep::{Grouping, Hop, Sweep, SweepBuilder};
+use std::io::BufRead;
+
+const ACQUISITION_START: &[u8] = b"# Acquisition start:";
+
+/// The hop being read, flushed into the sweep builder at a blank line
+#[derive(Default)]
+struct Block {
+    date: String,
+    time: String,
+    frequencies: Vec<f64>,
+    values: Vec<f32>,
+}
+
+impl Block {
+    fn flush<F>(&mut self, builder: &mut SweepBuilder, f: &mut F) -> Result<()>
+    where
+        F: FnMut(Sweep) -> Result<()>,
+    {
+        let (first, last) = match (self.frequencies.first(), self.frequencies.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return Ok(()),
+        };
+        let step = if self.frequencies.len() > 1 {
+            (last - first) / (self.frequencies.len() - 1) as f64
+        } else {
+            0.0
+        };
+        let hop = Hop {
+            freq_low: first.round() as u64,
+            freq_high: (last + step).round() as u64,
+            freq_step: step,
+            start: 0,
+        };
+        let finished = builder.push_hop(&self.date, &self.time, hop, &self.values);
+        self.frequencies.clear();
+        self.values.clear();
+        finished.map_or(Ok(()), f)
+    }
+}
+
+/// Calls `f` with every sweep of `reader`. A sweep ends when the frequency goes back down,
+/// so it doesn't matter whether blank lines separate hops or whole sweeps.
+pub(crate) fn for_each_sweep<R, F>(
+    reader: R,
+    options: &ReadOptions,
+    report: &mut Report,
+    mut f: F,
+) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(Sweep) -> Result<()>,
+{
+    let mut builder = SweepBuilder::new(Grouping::Frequency);
+    let mut block = Block::default();
+    parser::for_each_line_with_blanks(reader, |line| {
+        if line.is_empty() {
+            return block.flush(&mut builder, &mut f);
+        }
+        if line.starts_with(b"#") {
+            if let Some(start) = line.strip_prefix(ACQUISITION_START) {
+                block.flush(&mut builder, &mut f)?;
+                let start = String::from_utf8_lossy(trim(start));
+                let mut parts = start.split_whitespace();
+                block.date = parts.next().unwrap_or_default().to_string();
+                block.time = parts.next().unwrap_or_default().to_string();
+            }
+            return Ok(());
+        }
+        let mut fields = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|field| !field.is_empty());
+        let (frequency, power) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(frequency), Some(power), None) => (frequency, power),
+            (_, _, extra) if options.lenient => {
+                report.drop(if extra.is_some() {
+                    Problem::ExtraValues
+                } else {
+                    Problem::TooFewColumns
+                });
+                return Ok(());
+            }
+            _ => return Err(Error::csv(None, "Expected a frequency and a power")),
+        };
+        let frequency = match parse_frequency(frequency) {
+            Ok(frequency) => frequency,
+            Err(_) if options.lenient => {
+                report.drop(Problem::InvalidHeader);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        let power = match parser::parse_f32(power, 2) {
+            Ok(power) => power,
+            Err(_) if options.lenient => {
+                report.repair(Problem::InvalidValue);
+                f32::NAN
+            }
+            Err(err) => return Err(err),
+        };
+        block.frequencies.push(frequency);
+        block.values.push(power);
+        Ok(())
+    })?;
+    block.flush(&mut builder, &mut f)?;
+    builder.finish().map_or(Ok(()), f)
+}
+
+/// Whether `line` is a frequency and power pair
+pub(crate) fn is_pair(line: &[u8]) -> bool {
+    let fields = line
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|field| !field.is_empty())
+        .collect::<Vec<_>>();
+    fields.len() == 2
+        && parse_frequency(fields[0]).is_ok()
+        && parser::parse_f32(fields[1], 2).is_ok()
+}
+
+fn parse_frequency(field: &[u8]) -> Result<f64> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::number(1, String::from_utf8_lossy(field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"# rtl_power_fftw output
+# Acquisition start: 2019-08-17 22:37:25 UTC
+# Acquisition end: 2019-08-17 22:37:26 UTC
+#
+# frequency [Hz] power spectral density [dB/Hz]
+100000000 -40.5
+100500000 -41.5
+
+101000000 -42.5
+101500000 -43.5
+
+# Acquisition start: 2019-08-17 22:37:26 UTC
+100000000 -44.5
+100500000 -45.5
+";
+
+    fn read(data: &[u8], lenient: bool) -> (Result<Vec<Sweep>>, Report) {
+        let options = ReadOptions {
+            lenient,
+            ..ReadOptions::default()
+        };
+        let mut report = Report::default();
+        let mut sweeps = Vec::new();
+        let res = for_each_sweep(data, &options, &mut report, |sweep| {
+            sweeps.push(sweep);
+            Ok(())
+        });
+        (res.map(|_| sweeps), report)
+    }
+
+    #[test]
+    fn reads_blocks() {
+        let sweeps = read(DATA, false).0.unwrap();
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[0].values, vec![-40.5, -41.5, -42.5, -43.5]);
+        assert_eq!(sweeps[0].date, "2019-08-17");
+        assert_eq!(sweeps[0].time, "22:37:25");
+        assert_eq!(sweeps[0].hops[1].freq_low, 101_000_000);
+        assert_eq!(sweeps[0].hops[1].freq_high, 102_000_000);
+        assert_eq!(sweeps[0].hops[1].freq_step, 500_000.0);
+        assert_eq!(sweeps[1].time, "22:37:26");
+    }
+
+    #[test]
+    fn broken_lines() {
+        let data = b"100000000 -40.5\n100500000 x\n101000000\n";
+        let err = read(data, false).0.unwrap_err();
+        assert_eq!(err.position().map(|p| p.line), Some(2));
+        let (sweeps, report) = read(data, true);
+        assert_eq!(sweeps.unwrap()[0].width(), 2);
+        assert_eq!(report.repaired.get(&Problem::InvalidValue), Some(&1));
+        assert_eq!(report.dropped.get(&Problem::TooFewColumns), Some(&1));
+    }
+}
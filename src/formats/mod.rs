@@ -0,0 +1,507 @@
+//! Input formats of the different sweep tools, read into [`Sweep`]s.
+
+use crate::error::Result;
+use crate::lenient::{self, Problem, ReadOptions, Report};
+use crate::parser::{self, Line};
+use crate::sweep::{Grouping, Sweep, SweepBuilder};
+use crate::timezone::Clock;
+use chrono::{DateTime, Utc};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Cursor, Read},
+};
+
+mod fftw;
+mod soapy;
+
+/// How many bytes from the start of the input [`InputFormat::detect`] looks at,
+/// or more if the first line is longer
+pub const DETECT_LENGTH: usize = 8192;
+
+/// The tool that wrote a capture
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InputFormat {
+    /// rtl_power CSV, which has an extra bin at the end of each line
+    #[default]
+    RtlPower,
+    /// hackrf_sweep CSV, with the same columns as rtl_power, but without the extra bin.
+    /// Hops of a sweep aren't written in frequency order, and their timestamps are those of
+    /// the USB transfer they came in, so a sweep usually has several of them.
+    HackrfSweep,
+    /// soapy_power CSV, without the extra bin and with a timestamp for every hop
+    SoapyPower,
+    /// soapy_power's binary format
+    SoapyPowerBinary,
+    /// rtl_power_fftw text, with a frequency and power pair on every line
+    RtlPowerFftw,
+}
+
+impl InputFormat {
+    /// Guesses the format from `head`, the start of the input, or `None` if it doesn't look like any of them.
+    /// CSV formats are told apart by comparing the number of values with `freq_low`, `freq_high` and `freq_step`.
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(soapy::MAGIC) {
+            return Some(InputFormat::SoapyPowerBinary);
+        }
+        let mut lines = head.split(|&b| b == b'\n').map(parser::trim);
+        // The last line may be cut off in the middle, but the first one is complete, see [`head`]
+        let last = lines.next_back();
+        let mut lines = lines.chain(last.filter(|_| head.len() < DETECT_LENGTH));
+        let first = lines.find(|line| !line.is_empty())?;
+        if first.starts_with(b"#") || !first.contains(&b',') {
+            let data = std::iter::once(first)
+                .chain(lines)
+                .find(|line| !line.is_empty() && !line.starts_with(b"#"));
+            return match data {
+                Some(data) if fftw::is_pair(data) => Some(InputFormat::RtlPowerFftw),
+                Some(_) => None,
+                // Only comments so far
+                None => Some(InputFormat::RtlPowerFftw),
+            };
+        }
+        let line = Line::parse(first).ok()?;
+        let bins = line.bins()?;
+        let values = line.values().count();
+        if values == bins + 1 {
+            Some(InputFormat::RtlPower)
+        } else if values != bins {
+            None
+        } else if first.split(|&b| b == b',').nth(2)?.contains(&b'.') {
+            // soapy_power writes frequencies as floats
+            Some(InputFormat::SoapyPower)
+        } else {
+            Some(InputFormat::HackrfSweep)
+        }
+    }
+
+    /// Whether lines contain one value more than their frequency range describes
+    pub fn trailing_bin(self) -> bool {
+        self == InputFormat::RtlPower
+    }
+
+    /// Whether timestamps are written in UTC rather than local time, like soapy_power's Unix time
+    /// and the `UTC` rtl_power_fftw notes in its comments
+    pub fn writes_utc(self) -> bool {
+        matches!(
+            self,
+            InputFormat::SoapyPowerBinary | InputFormat::RtlPowerFftw
+        )
+    }
+
+    fn grouping(self) -> Grouping {
+        match self {
+            InputFormat::RtlPower => Grouping::Timestamp,
+            _ => Grouping::Frequency,
+        }
+    }
+
+    /// Splits in-memory input into at most `parts` chunks that can be read in parallel.
+    /// Sweeps of formats that don't group hops by timestamp can't be found without reading, so they stay in one chunk.
+    pub(crate) fn split(self, data: &[u8], parts: usize) -> Vec<&[u8]> {
+        match self.grouping() {
+            Grouping::Timestamp => parser::split_sweeps(data, parts),
+            Grouping::Frequency if data.is_empty() => Vec::new(),
+            Grouping::Frequency => vec![data],
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputFormat::RtlPower => "rtl_power",
+            InputFormat::HackrfSweep => "hackrf_sweep",
+            InputFormat::SoapyPower => "soapy_power",
+            InputFormat::SoapyPowerBinary => "soapy_power_bin",
+            InputFormat::RtlPowerFftw => "rtl_power_fftw",
+        })
+    }
+}
+
+/// Where the first line with anything in it ends, after its newline
+fn first_line_end(data: &[u8]) -> Option<usize> {
+    let start = data.iter().position(|b| !b.is_ascii_whitespace())?;
+    let end = data[start..].iter().position(|&b| b == b'\n')?;
+    Some(start + end + 1)
+}
+
+/// The start of in-memory input that [`InputFormat::detect`] looks at:
+/// [`DETECT_LENGTH`] bytes, or more to include all of the first line
+pub(crate) fn head(data: &[u8]) -> &[u8] {
+    let end = first_line_end(data).unwrap_or(data.len());
+    &data[..end.max(DETECT_LENGTH).min(data.len())]
+}
+
+/// Reads the start of `reader` that [`InputFormat::detect`] looks at, like [`head`] of in-memory input,
+/// but possibly a bit more
+pub(crate) fn read_head<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    reader
+        .by_ref()
+        .take(DETECT_LENGTH as u64)
+        .read_to_end(&mut head)?;
+    while first_line_end(&head).is_none() {
+        let read = reader
+            .by_ref()
+            .take(DETECT_LENGTH as u64)
+            .read_to_end(&mut head)?;
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(head)
+}
+
+/// Detects the format of `file` from its head, unless it was set, and returns a reader from its start
+pub(crate) fn read_start<R: Read>(
+    mut file: R,
+    options: &ReadOptions,
+) -> Result<(ReadOptions, impl BufRead)> {
+    let head = read_head(&mut file)?;
+    let options = options.for_input(&head);
+    Ok((options, BufReader::new(Cursor::new(head).chain(file))))
+}
+
+/// Leaves out a sweep outside the selected times, resamples it if its values aren't linear in frequency
+/// and crops it to the selected frequencies. In lenient mode, sweeps that can't be resampled are
+/// left out too, and counted in `unresampled`.
+fn prepare(
+    sweep: Sweep,
+    options: &ReadOptions,
+    clock: &mut Clock,
+    unresampled: &mut usize,
+) -> Result<Option<Sweep>> {
+    if options.start.is_some() || options.end.is_some() {
+        let time = sweep.timestamp().and_then(|time| clock.instant(time));
+        if !options.selects(time) {
+            return Ok(None);
+        }
+    }
+    let sweep = match sweep.linearize(options.overlap) {
+        Ok(sweep) => sweep,
+        Err(_) if options.lenient => {
+            *unresampled += 1;
+            return Ok(None);
+        }
+        Err(err) => return Err(err),
+    };
+    if options.freq_min.is_some() || options.freq_max.is_some() {
+        Ok(Some(sweep.crop(
+            options.freq_min.unwrap_or(f64::NEG_INFINITY),
+            options.freq_max.unwrap_or(f64::INFINITY),
+        )))
+    } else {
+        Ok(Some(sweep))
+    }
+}
+
+/// Records the sweeps [`prepare`] left out because they couldn't be resampled
+fn report_unresampled(report: &mut Report, unresampled: usize) {
+    for _ in 0..unresampled {
+        report.drop(Problem::UnevenSteps);
+    }
+}
+
+/// When the first sweep in `head`, the start of an input, was measured
+pub(crate) fn first_timestamp(head: &[u8], options: &ReadOptions) -> Option<DateTime<Utc>> {
+    // The end of `head` is usually cut off in the middle of a line
+    let options = ReadOptions {
+        lenient: true,
+        start: None,
+        end: None,
+        ..options.clone()
+    };
+    let mut first = None;
+    let _ = for_each_sweep(head, &options, &mut Report::default(), |sweep| {
+        first = first.or_else(|| sweep.timestamp());
+        Ok(())
+    });
+    first.and_then(|first| Clock::new(options.timezone()).instant(first))
+}
+
+/// Calls `f` with every sweep of `reader` that [`prepare`] keeps
+pub(crate) fn for_each_sweep<R, F>(
+    reader: R,
+    options: &ReadOptions,
+    report: &mut Report,
+    mut f: F,
+) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(Sweep) -> Result<()>,
+{
+    let mut clock = Clock::new(options.timezone());
+    let mut unresampled = 0;
+    let result = for_each_raw_sweep(reader, options, report, |sweep| {
+        match prepare(sweep, options, &mut clock, &mut unresampled)? {
+            Some(sweep) => f(sweep),
+            None => Ok(()),
+        }
+    });
+    report_unresampled(report, unresampled);
+    result
+}
+
+/// Calls `f` with every sweep of `reader`, as it was read
+fn for_each_raw_sweep<R, F>(
+    reader: R,
+    options: &ReadOptions,
+    report: &mut Report,
+    mut f: F,
+) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(Sweep) -> Result<()>,
+{
+    match options.format() {
+        InputFormat::SoapyPowerBinary => return soapy::for_each_sweep(reader, options, report, f),
+        InputFormat::RtlPowerFftw => return fftw::for_each_sweep(reader, options, report, f),
+        _ => {}
+    }
+    let mut builder = SweepBuilder::new(options.format().grouping());
+    let mut values = Vec::new();
+    parser::for_each_line(reader, |raw| {
+        if let Some(line) = lenient::read_line(raw, options, &mut values, report)? {
+            if let Some(sweep) = builder.push(&line, &values) {
+                f(sweep)?;
+            }
+        }
+        Ok(())
+    })?;
+    builder.finish().map_or(Ok(()), f)
+}
+
+/// Calls `f` with every sweep of `chunk` that [`prepare`] keeps, whatever its time.
+/// `chunk` is a part of `data` split by [`InputFormat::split`]. Which repeated hour a time is in
+/// when clocks go back can only be told from the sweeps before it, so the caller leaves out
+/// sweeps outside the selected times once it has them all in order.
+pub(crate) fn for_each_sweep_in<F>(
+    data: &[u8],
+    chunk: &[u8],
+    options: &ReadOptions,
+    report: &mut Report,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(Sweep) -> Result<()>,
+{
+    let options = &options.all_times();
+    if options.format().grouping() == Grouping::Frequency {
+        // The whole input is a single chunk, so positions are relative to the start of `data`
+        return for_each_sweep(chunk, options, report, f);
+    }
+    let mut clock = Clock::new(options.timezone());
+    let mut unresampled = 0;
+    let mut f = |sweep: Sweep| match prepare(sweep, options, &mut clock, &mut unresampled)? {
+        Some(sweep) => f(sweep),
+        None => Ok(()),
+    };
+    let mut builder = SweepBuilder::new(options.format().grouping());
+    let mut values = Vec::new();
+    let result = parser::for_each_line_in(data, chunk, |raw| {
+        if let Some(line) = lenient::read_line(raw, options, &mut values, report)? {
+            if let Some(sweep) = builder.push(&line, &values) {
+                f(sweep)?;
+            }
+        }
+        Ok(())
+    })
+    .and_then(|_| builder.finish().map_or(Ok(()), &mut f));
+    report_unresampled(report, unresampled);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timezone::TimeBound;
+
+    /// Two sweeps, each in two transfers, with hops interleaved the way hackrf_sweep writes them
+    const HACKRF: &[u8] = b"2019-08-17, 22:37:25.123456, 0, 5, 2.5, 20, 1.0, 2.0
+2019-08-17, 22:37:25.123456, 10, 15, 2.5, 20, 5.0, 6.0
+2019-08-17, 22:37:25.234567, 5, 10, 2.5, 20, 3.0, 4.0
+2019-08-17, 22:37:25.234567, 15, 20, 2.5, 20, 7.0, 8.0
+2019-08-17, 22:37:26.123456, 0, 5, 2.5, 20, 11.0, 12.0
+2019-08-17, 22:37:26.123456, 10, 15, 2.5, 20, 15.0, 16.0
+2019-08-17, 22:37:26.234567, 5, 10, 2.5, 20, 13.0, 14.0
+2019-08-17, 22:37:26.234567, 15, 20, 2.5, 20, 17.0, 18.0
+";
+
+    fn sweeps(data: &[u8], format: InputFormat) -> Vec<Sweep> {
+        let options = ReadOptions {
+            format: Some(format),
+            ..ReadOptions::default()
+        };
+        let mut sweeps = Vec::new();
+        let mut report = Report::default();
+        for_each_sweep(data, &options, &mut report, |sweep| {
+            sweeps.push(sweep);
+            Ok(())
+        })
+        .unwrap();
+        let mut in_chunks = Vec::new();
+        for chunk in format.split(data, 3) {
+            for_each_sweep_in(data, chunk, &options, &mut report, |sweep| {
+                in_chunks.push(sweep);
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(sweeps, in_chunks);
+        sweeps
+    }
+
+    #[test]
+    fn drops_sweeps_with_tiny_steps() {
+        let data = b"2019-08-17, 22:37:25, 0, 20, 10, 1, 1.0, 2.0, 0
+2019-08-17, 22:37:25, 30, 30, 0.0000001, 1, 3.0
+2019-08-17, 22:37:35, 0, 20, 10, 1, 1.0, 2.0, 0
+";
+        let options = ReadOptions {
+            format: Some(InputFormat::RtlPower),
+            ..ReadOptions::default()
+        };
+        let mut report = Report::default();
+        assert!(for_each_sweep(&data[..], &options, &mut report, |_| Ok(())).is_err());
+
+        let options = ReadOptions {
+            lenient: true,
+            ..options
+        };
+        let mut count = 0;
+        for_each_sweep(&data[..], &options, &mut report, |_| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(report.dropped.get(&Problem::UnevenSteps), Some(&1));
+    }
+
+    #[test]
+    fn hackrf_sweep() {
+        let sweeps = sweeps(HACKRF, InputFormat::HackrfSweep);
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(
+            sweeps[0].values,
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]
+        );
+        assert_eq!(sweeps[1].values[7], 18.0);
+        assert_eq!(sweeps[1].time, "22:37:26.123456");
+    }
+
+    #[test]
+    fn rtl_power_drops_trailing_bin() {
+        let data = b"2019-08-17, 22:37:25, 10, 20, 5, 1, 3.0, 4.0, 9.0
+2019-08-17, 22:37:25, 0, 10, 5, 1, 1.0, 2.0, 9.0
+";
+        let sweeps = sweeps(data, InputFormat::RtlPower);
+        assert_eq!(sweeps[0].values, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn selects_times() {
+        let data = b"2019-08-17, 22:37:25, 0, 2, 1, 1, 1.0, 1.0, 0
+2019-08-17, 22:37:35, 0, 2, 1, 1, 2.0, 2.0, 0
+2019-08-17, 22:37:45, 0, 2, 1, 1, 3.0, 3.0, 0
+2019-08-17, 22:37:55, 0, 2, 1, 1, 4.0, 4.0, 0
+";
+        let end = "2019-08-17T22:37:45Z".parse().unwrap();
+        let options = ReadOptions {
+            start: Some(TimeBound::After(chrono::Duration::seconds(5))),
+            end: Some(TimeBound::At(end)),
+            ..ReadOptions::default()
+        }
+        .for_input(data);
+        assert_eq!(
+            options.start,
+            Some(TimeBound::At("2019-08-17T22:37:30Z".parse().unwrap()))
+        );
+        let mut values = Vec::new();
+        for_each_sweep(&data[..], &options, &mut Report::default(), |sweep| {
+            values.push(sweep.values[0]);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(values, vec![2.0, 3.0]);
+    }
+
+    #[test]
+    fn soapy_power() {
+        let data = b"2019-08-17, 22:37:25.1, 100000000.0, 100200000.0, 100000.0, 10, 1.0, 2.0
+2019-08-17, 22:37:25.2, 100200000.0, 100400000.0, 100000.0, 10, 3.0, 4.0
+2019-08-17, 22:37:25.3, 100000000.0, 100200000.0, 100000.0, 10, 5.0, 6.0
+";
+        let sweeps = sweeps(data, InputFormat::SoapyPower);
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[0].values, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(sweeps[0].time, "22:37:25.1");
+    }
+
+    #[test]
+    fn binary_and_text() {
+        let binary = [
+            soapy::tests::record(0.0, 100e6, 1e6, &[1.0, 2.0]),
+            soapy::tests::record(0.0, 100e6, 1e6, &[3.0, 4.0]),
+        ]
+        .concat();
+        let from_binary = sweeps(&binary, InputFormat::SoapyPowerBinary);
+        assert_eq!(from_binary.len(), 2);
+        assert_eq!(from_binary[1].values, vec![3.0, 4.0]);
+
+        let text = b"100000000 1.0\n100500000 2.0\n\n100000000 3.0\n100500000 4.0\n";
+        let from_text = sweeps(text, InputFormat::RtlPowerFftw);
+        assert_eq!(from_text.len(), 2);
+        assert_eq!(from_text[1].values, from_binary[1].values);
+        assert_eq!(from_text[1].hops[0].freq_step, 500_000.0);
+    }
+
+    #[test]
+    fn detect() {
+        let detect = |head: &[u8]| InputFormat::detect(head);
+        assert_eq!(
+            detect(b"2019-08-17, 22:37:25, 0, 4, 1, 1, 1, 2, 3, 4, 5\n"),
+            Some(InputFormat::RtlPower)
+        );
+        assert_eq!(detect(HACKRF), Some(InputFormat::HackrfSweep));
+        assert_eq!(
+            detect(b"2019-08-17, 22:37:25.1, 0.0, 4.0, 1.0, 1, 1, 2, 3, 4\n"),
+            Some(InputFormat::SoapyPower)
+        );
+        assert_eq!(
+            detect(&soapy::tests::record(0.0, 100e6, 1e6, &[1.0])),
+            Some(InputFormat::SoapyPowerBinary)
+        );
+        assert_eq!(
+            detect(b"# rtl_power_fftw output\n#\n100000000 -40.5\n"),
+            Some(InputFormat::RtlPowerFftw)
+        );
+        assert_eq!(
+            detect(b"100000000\t-40.5\n"),
+            Some(InputFormat::RtlPowerFftw)
+        );
+        assert_eq!(detect(b"2019-08-17, 22:37:25, 0, 4, 1, 1, 1, 2\n"), None);
+        assert_eq!(detect(b"hello world\n"), None);
+        assert_eq!(detect(b""), None);
+    }
+
+    #[test]
+    fn detects_long_lines() {
+        let values = vec!["-40.5"; 2048].join(", ");
+        let line = format!(
+            "2019-08-17, 22:37:25.1, 100000000.0, 102048000.0, 1000.0, 10, {}\n",
+            values
+        );
+        let data = line.repeat(3).into_bytes();
+        assert!(line.len() > DETECT_LENGTH);
+        assert_eq!(head(&data).len(), line.len());
+        let mut reader = &data[..];
+        assert!(read_head(&mut reader).unwrap().starts_with(head(&data)));
+        assert_eq!(
+            InputFormat::detect(head(&data)),
+            Some(InputFormat::SoapyPower)
+        );
+        let (options, _) = read_start(&data[..], &ReadOptions::default()).unwrap();
+        assert_eq!(options.format, Some(InputFormat::SoapyPower));
+    }
+}
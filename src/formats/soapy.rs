@@ -0,0 +1,321 @@
+//! soapy_power's binary format: a fixed little-endian header followed by `f32` values, for every hop.
+
+use crate::error::{Error, Result};
+use crate::lenient::{Problem, ReadOptions, Report};
+use crate::sweep::{Grouping, Hop, Sweep, SweepBuilder};
+use chrono::DateTime;
+use std::{
+    convert::TryInto,
+    io::{BufRead, Read},
+};
+
+pub(crate) const MAGIC: &[u8] = b"SDRFF";
+const VERSION: u8 = 2;
+/// Magic, version, time_start, time_stop, start, stop, step, samples, size and two bytes of padding
+const HEADER_SIZE: usize = 64;
+
+fn f64_at(header: &[u8], offset: usize) -> f64 {
+    f64::from_le_bytes(header[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+fn u64_at(header: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(header[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// Fills `buf` as far as possible, returning how many bytes were read before the end of the input
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// Number of values the header describes, from the first and last frequency and the step
+fn expected_values(header: &[u8]) -> Option<u64> {
+    let (start, stop, step) = (f64_at(header, 22), f64_at(header, 30), f64_at(header, 38));
+    let values = (stop - start) / step + 1.0;
+    (values.is_finite() && values >= 1.0).then(|| values.round() as u64)
+}
+
+/// Checks that the header describes as many values as `size` holds, so a corrupt one doesn't
+/// make the reader allocate or skip more than the record has
+fn check_size(header: &[u8]) -> std::result::Result<u64, String> {
+    let size = u64_at(header, 54);
+    if !size.is_multiple_of(4) {
+        return Err(format!("Data size {} isn't a multiple of 4", size));
+    }
+    match expected_values(header) {
+        // soapy_power's last frequency may be that of the last bin or the end of it
+        Some(values) if (size / 4).abs_diff(values) <= 1 => Ok(size),
+        Some(values) => Err(format!(
+            "Data size {} doesn't fit the {} values its frequencies describe",
+            size, values
+        )),
+        None => Err("Invalid frequencies".to_string()),
+    }
+}
+
+/// Skips to the next record after a corrupt one whose header was read into `header`.
+/// Looks for the magic and version, fills `header` from there and returns how many bytes were skipped
+/// and how many of `header` were filled, or `None` at the end of the input.
+fn resync<R: BufRead>(
+    reader: &mut R,
+    header: &mut [u8; HEADER_SIZE],
+) -> Result<Option<(usize, usize)>> {
+    let marker = [MAGIC, &[VERSION]].concat();
+    let mut window = header[1..].to_vec();
+    let mut skipped = 1;
+    loop {
+        if let Some(found) = window
+            .windows(marker.len())
+            .position(|bytes| bytes == marker)
+        {
+            let start = &window[found..];
+            header[..start.len()].copy_from_slice(start);
+            let read = read_full(reader, &mut header[start.len()..])?;
+            return Ok(Some((skipped + found, start.len() + read)));
+        }
+        // Keep what could be the start of the marker
+        let keep = window.len().min(marker.len() - 1);
+        skipped += window.len() - keep;
+        window.drain(..window.len() - keep);
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        // Not more than a header can hold, so nothing after the next header is read
+        let take = buf.len().min(HEADER_SIZE - marker.len());
+        window.extend_from_slice(&buf[..take]);
+        reader.consume(take);
+    }
+}
+
+/// Calls `f` with every sweep of `reader`. A sweep ends when the frequency goes back down.
+pub(crate) fn for_each_sweep<R, F>(
+    mut reader: R,
+    options: &ReadOptions,
+    report: &mut Report,
+    mut f: F,
+) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(Sweep) -> Result<()>,
+{
+    let mut builder = SweepBuilder::new(Grouping::Frequency);
+    let mut header = [0; HEADER_SIZE];
+    let mut data = Vec::new();
+    let mut values = Vec::new();
+    let mut offset = 0;
+    // Bytes of `header` that were already read while skipping a corrupt record
+    let mut pending = None;
+    loop {
+        let error = |message: String| Error::Binary { offset, message };
+        let read = match pending.take() {
+            Some(read) => read,
+            None => read_full(&mut reader, &mut header)?,
+        };
+        if read == 0 {
+            break;
+        }
+        if read < HEADER_SIZE {
+            if options.lenient {
+                report.drop(Problem::Truncated);
+                break;
+            }
+            return Err(error(format!(
+                "Expected a header of {} bytes, found {}",
+                HEADER_SIZE, read
+            )));
+        }
+        let valid = if !header.starts_with(MAGIC) || header[MAGIC.len()] != VERSION {
+            Err(format!(
+                "Expected the magic {:?} and version {}",
+                String::from_utf8_lossy(MAGIC),
+                VERSION
+            ))
+        } else {
+            check_size(&header)
+        };
+        let size = match valid {
+            Ok(size) => size,
+            Err(_) if options.lenient => {
+                report.drop(Problem::Corrupt);
+                match resync(&mut reader, &mut header)? {
+                    Some((skipped, read)) => {
+                        offset += skipped as u64;
+                        pending = Some(read);
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            Err(message) => return Err(error(message)),
+        };
+        let time_start = f64_at(&header, 6);
+        let start = f64_at(&header, 22);
+        let step = f64_at(&header, 38);
+        // Grows only as far as the input goes, even if it ends early
+        data.clear();
+        let read = reader.by_ref().take(size).read_to_end(&mut data)?;
+        if read < size as usize && !options.lenient {
+            return Err(error(format!(
+                "Expected {} bytes of data, found {}",
+                size, read
+            )));
+        }
+        values.clear();
+        values.extend(
+            data[..read - read % 4]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().expect("4 bytes"))),
+        );
+        if read < size as usize {
+            report.repair(Problem::MissingValues);
+            values.resize(size as usize / 4, f32::NAN);
+        }
+        let (date, time) = date_time(time_start);
+        let hop = Hop {
+            freq_low: start.round() as u64,
+            freq_high: (start + step * values.len() as f64).round() as u64,
+            freq_step: step,
+            start: 0,
+        };
+        if let Some(sweep) = builder.push_hop(&date, &time, hop, &values) {
+            f(sweep)?;
+        }
+        offset += (HEADER_SIZE + read) as u64;
+    }
+    builder.finish().map_or(Ok(()), f)
+}
+
+/// UTC date and time of a Unix timestamp, formatted like in the CSV formats.
+/// Times chrono can't represent are left empty, like a missing timestamp.
+fn date_time(timestamp: f64) -> (String, String) {
+    match DateTime::from_timestamp_micros((timestamp * 1e6).round() as i64) {
+        Some(time) => (
+            time.format("%Y-%m-%d").to_string(),
+            time.format("%H:%M:%S%.6f").to_string(),
+        ),
+        None => (String::new(), String::new()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Encodes a record the way soapy_power does
+    pub(crate) fn record(time: f64, start: f64, step: f64, values: &[f32]) -> Vec<u8> {
+        let mut record = MAGIC.to_vec();
+        record.push(VERSION);
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(&(time + 0.5).to_le_bytes());
+        record.extend_from_slice(&start.to_le_bytes());
+        record.extend_from_slice(&(start + step * (values.len() - 1) as f64).to_le_bytes());
+        record.extend_from_slice(&step.to_le_bytes());
+        record.extend_from_slice(&1000u64.to_le_bytes());
+        record.extend_from_slice(&(values.len() as u64 * 4).to_le_bytes());
+        record.extend_from_slice(&[0, 0]);
+        for value in values {
+            record.extend_from_slice(&value.to_le_bytes());
+        }
+        record
+    }
+
+    fn read(data: &[u8], lenient: bool) -> (Result<Vec<Sweep>>, Report) {
+        let options = ReadOptions {
+            lenient,
+            ..ReadOptions::default()
+        };
+        let mut report = Report::default();
+        let mut sweeps = Vec::new();
+        let res = for_each_sweep(data, &options, &mut report, |sweep| {
+            sweeps.push(sweep);
+            Ok(())
+        });
+        (res.map(|_| sweeps), report)
+    }
+
+    #[test]
+    fn reads_records() {
+        let data = [
+            record(1_566_081_445.25, 100e6, 1e6, &[1.0, 2.0]),
+            record(1_566_081_445.5, 102e6, 1e6, &[3.0, 4.0]),
+            record(1_566_081_446.0, 100e6, 1e6, &[5.0, 6.0]),
+        ]
+        .concat();
+        let sweeps = read(&data, false).0.unwrap();
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[0].values, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(sweeps[0].date, "2019-08-17");
+        assert_eq!(sweeps[0].time, "22:37:25.250000");
+        assert_eq!(sweeps[0].hops[1].freq_low, 102_000_000);
+        assert_eq!(sweeps[0].hops[1].freq_high, 104_000_000);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = [
+            record(0.0, 100e6, 1e6, &[1.0, 2.0]),
+            record(0.0, 102e6, 1e6, &[3.0, 4.0]),
+        ]
+        .concat();
+        let cut = &data[..data.len() - 3];
+        assert!(matches!(
+            read(cut, false).0,
+            Err(Error::Binary { offset: 72, .. })
+        ));
+        let (sweeps, report) = read(cut, true);
+        let sweeps = sweeps.unwrap();
+        assert_eq!(sweeps[0].values[..3], [1.0, 2.0, 3.0]);
+        assert!(sweeps[0].values[3].is_nan());
+        assert_eq!(report.repaired.get(&Problem::MissingValues), Some(&1));
+
+        let (sweeps, report) = read(&data[..80], true);
+        assert_eq!(sweeps.unwrap()[0].width(), 2);
+        assert_eq!(report.dropped.get(&Problem::Truncated), Some(&1));
+    }
+
+    #[test]
+    fn dates() {
+        let date = |timestamp| date_time(timestamp).0;
+        assert_eq!(date(0.0), "1970-01-01");
+        assert_eq!(date(18_125.0 * 86400.0), "2019-08-17");
+        assert_eq!(date(-1.0), "1969-12-31");
+        assert_eq!(date_time(-0.25).1, "23:59:59.750000");
+        assert_eq!(date(11_016.0 * 86400.0), "2000-02-29");
+    }
+
+    #[test]
+    fn corrupt_sizes() {
+        let mut corrupt = record(0.0, 100e6, 1e6, &[1.0, 2.0]);
+        corrupt[54..62].copy_from_slice(&(u64::MAX - 3).to_le_bytes());
+        let data = [
+            record(0.0, 100e6, 1e6, &[1.0, 2.0]),
+            corrupt,
+            record(1.0, 100e6, 1e6, &[3.0, 4.0]),
+        ]
+        .concat();
+        assert!(matches!(
+            read(&data, false).0,
+            Err(Error::Binary { offset: 72, .. })
+        ));
+        let (sweeps, report) = read(&data, true);
+        let sweeps = sweeps.unwrap();
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[1].values, vec![3.0, 4.0]);
+        assert_eq!(report.dropped.get(&Problem::Corrupt), Some(&1));
+
+        let mut odd = record(0.0, 100e6, 1e6, &[1.0]);
+        odd[54..62].copy_from_slice(&3u64.to_le_bytes());
+        let (sweeps, report) = read(&odd, true);
+        assert!(sweeps.unwrap().is_empty());
+        assert_eq!(report.dropped.get(&Problem::Corrupt), Some(&1));
+    }
+}
@@ -0,0 +1,436 @@
+//! Repairing or dropping malformed lines, such as the partial last line of a capture cut off by a power loss.
+
+use crate::error::{Error, Result};
+use crate::formats::{self, InputFormat};
+use crate::parser::{self, Line};
+use crate::sweep::Overlap;
+use crate::timezone::{TimeBound, Tz};
+use chrono::{DateTime, Utc};
+use log::*;
+use std::{collections::BTreeMap, fmt};
+
+/// How many sweeps with a different width are listed as warnings, the rest is only logged with debug output
+const MISMATCHES_LOGGED: usize = 20;
+
+/// A line is only padded with NaN if it has at least this share of the values its header describes.
+/// With fewer, the header is more likely broken, and padding could take any amount of memory.
+const MIN_PRESENT: f64 = 0.5;
+
+/// Settings shared by the preprocessing and processing passes, so both read the file the same way
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// Skip or repair malformed lines instead of failing
+    pub lenient: bool,
+    /// Tool that wrote the input, `None` to detect it from the first lines
+    pub format: Option<InputFormat>,
+    /// Time zone of the dates and times in the input, for tools that write local time
+    pub timezone: Tz,
+    /// How to combine hops that overlap, when resampling them onto one frequency grid
+    pub overlap: Overlap,
+    /// What to do with sweeps that have a different number of bins than the others
+    pub mismatch: WidthPolicy,
+    /// Lowest frequency to read, in Hz
+    pub freq_min: Option<f64>,
+    /// Highest frequency to read, in Hz
+    pub freq_max: Option<f64>,
+    /// Read only sweeps from this time on
+    pub start: Option<TimeBound>,
+    /// Read only sweeps up to this time
+    pub end: Option<TimeBound>,
+}
+
+/// What to do with a sweep whose width differs from the most common one
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WidthPolicy {
+    /// Fill missing bins with NaN and cut off extra ones
+    #[default]
+    Pad,
+    /// Leave the sweep out of the image
+    Drop,
+}
+
+impl ReadOptions {
+    /// Format to read the input as, rtl_power if it wasn't set or detected
+    pub(crate) fn format(&self) -> InputFormat {
+        self.format.unwrap_or_default()
+    }
+
+    /// Whether only a part of the input is read
+    pub(crate) fn crops(&self) -> bool {
+        self.freq_min.is_some()
+            || self.freq_max.is_some()
+            || self.start.is_some()
+            || self.end.is_some()
+    }
+
+    /// Whether a sweep measured at `time` is read. Offsets that weren't counted from a start don't exclude anything.
+    pub(crate) fn includes(&self, time: DateTime<Utc>) -> bool {
+        let after_start = match self.start {
+            Some(TimeBound::At(start)) => time >= start,
+            _ => true,
+        };
+        let before_end = match self.end {
+            Some(TimeBound::At(end)) => time <= end,
+            _ => true,
+        };
+        after_start && before_end
+    }
+
+    /// Whether a sweep measured at `time`, if its timestamp could be read, is read.
+    /// Sweeps without one are only read if no times are selected.
+    pub(crate) fn selects(&self, time: Option<DateTime<Utc>>) -> bool {
+        if self.start.is_none() && self.end.is_none() {
+            return true;
+        }
+        time.is_some_and(|time| self.includes(time))
+    }
+
+    /// These options without the selected times, for reading sweeps whose times are checked later
+    pub(crate) fn all_times(&self) -> Self {
+        Self {
+            start: None,
+            end: None,
+            ..self.clone()
+        }
+    }
+
+    /// Time zone to read timestamps in, UTC for formats that always write it
+    pub(crate) fn timezone(&self) -> Tz {
+        if self.format().writes_utc() {
+            Tz::UTC
+        } else {
+            self.timezone
+        }
+    }
+
+    /// These options for an input that starts with `head`: with its format detected, unless it was set,
+    /// and time offsets counted from its first sweep
+    pub fn for_input(&self, head: &[u8]) -> Self {
+        let options = Self {
+            format: self.format.or_else(|| InputFormat::detect(head)),
+            ..self.clone()
+        };
+        let offset = |bound| matches!(bound, Some(TimeBound::After(_)));
+        if offset(options.start) || offset(options.end) {
+            options.starting_at(formats::first_timestamp(head, &options))
+        } else {
+            options
+        }
+    }
+
+    /// These options with time offsets counted from `first`, when the first sweep was measured
+    pub fn starting_at(&self, first: Option<DateTime<Utc>>) -> Self {
+        let count = |bound| match (bound, first) {
+            (Some(TimeBound::After(offset)), Some(first)) => Some(TimeBound::At(first + offset)),
+            (bound, _) => bound,
+        };
+        Self {
+            start: count(self.start),
+            end: count(self.end),
+            ..self.clone()
+        }
+    }
+}
+
+/// Why a line was dropped or repaired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Problem {
+    /// Less than the header columns and one value
+    TooFewColumns,
+    /// Date, time or frequencies couldn't be parsed
+    InvalidHeader,
+    /// A value couldn't be parsed, it was replaced with NaN
+    InvalidValue,
+    /// Less values than `freq_low`, `freq_high` and `freq_step` describe, the rest was filled with NaN
+    MissingValues,
+    /// More values than `freq_low`, `freq_high` and `freq_step` describe
+    ExtraValues,
+    /// A binary record ends before its header does
+    Truncated,
+    /// A binary record's header doesn't fit its data, it was skipped up to the next record
+    Corrupt,
+    /// A sweep has a different number of bins than the others
+    WidthMismatch,
+    /// A sweep's hops have such different steps that resampling them onto one grid would take far more bins than values
+    UnevenSteps,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Problem::TooFewColumns => "too few columns",
+            Problem::InvalidHeader => "invalid date, time or frequency",
+            Problem::InvalidValue => "unparsable values",
+            Problem::MissingValues => "missing values",
+            Problem::ExtraValues => "more values than the frequency range allows",
+            Problem::Truncated => "truncated record",
+            Problem::Corrupt => "corrupt record",
+            Problem::WidthMismatch => "sweep width differs from the other sweeps",
+            Problem::UnevenSteps => "hop steps too different to resample",
+        })
+    }
+}
+
+/// A sweep with a different number of bins than the others
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// When the sweep was measured, in the time zone of the input
+    pub timestamp: String,
+    pub width: usize,
+    pub expected: usize,
+}
+
+/// Counts of lines that were dropped or repaired in lenient mode, and sweeps whose width didn't match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub dropped: BTreeMap<Problem, usize>,
+    pub repaired: BTreeMap<Problem, usize>,
+    pub mismatched: Vec<Mismatch>,
+}
+
+impl Report {
+    pub(crate) fn drop(&mut self, problem: Problem) {
+        *self.dropped.entry(problem).or_default() += 1;
+    }
+
+    pub(crate) fn repair(&mut self, problem: Problem) {
+        *self.repaired.entry(problem).or_default() += 1;
+    }
+
+    pub(crate) fn mismatch(&mut self, mismatch: Mismatch, policy: WidthPolicy) {
+        match policy {
+            WidthPolicy::Pad => self.repair(Problem::WidthMismatch),
+            WidthPolicy::Drop => self.drop(Problem::WidthMismatch),
+        }
+        self.mismatched.push(mismatch);
+    }
+
+    pub fn merge(mut a: Self, b: Self) -> Self {
+        for (problem, count) in b.dropped {
+            *a.dropped.entry(problem).or_default() += count;
+        }
+        for (problem, count) in b.repaired {
+            *a.repaired.entry(problem).or_default() += count;
+        }
+        a.mismatched.extend(b.mismatched);
+        a
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dropped.is_empty() && self.repaired.is_empty() && self.mismatched.is_empty()
+    }
+
+    /// Logs a line for each kind of problem found
+    pub fn log(&self) {
+        for (problem, count) in self.dropped.iter() {
+            warn!("Dropped {} lines: {}", count, problem);
+        }
+        for (problem, count) in self.repaired.iter() {
+            warn!("Repaired {} lines: {}", count, problem);
+        }
+        for (i, mismatch) in self.mismatched.iter().enumerate() {
+            let message = format!(
+                "Sweep at {} has {} bins instead of {}",
+                mismatch.timestamp, mismatch.width, mismatch.expected
+            );
+            if i < MISMATCHES_LOGGED {
+                warn!("{}", message);
+            } else {
+                debug!("{}", message);
+            }
+        }
+        if self.mismatched.len() > MISMATCHES_LOGGED {
+            warn!(
+                "{} more sweeps have a different width, run with -vvv to list them",
+                self.mismatched.len() - MISMATCHES_LOGGED
+            );
+        }
+    }
+}
+
+/// Parses `raw` and its values, without rtl_power's extra trailing bin, into `values`.
+/// Formats without that bin keep all values.
+/// In lenient mode, broken lines are repaired or dropped, returning `None`, and recorded in `report`.
+pub(crate) fn read_line<'a>(
+    raw: &'a [u8],
+    options: &ReadOptions,
+    values: &mut Vec<f32>,
+    report: &mut Report,
+) -> Result<Option<Line<'a>>> {
+    values.clear();
+    if !options.lenient {
+        let line = Line::parse(raw)?;
+        if line.bins().is_none() {
+            return Err(Error::geometry(format!(
+                "Can't split {} Hz to {} Hz into steps of {} Hz",
+                line.freq_low, line.freq_high, line.freq_step
+            )));
+        }
+        for value in line.values() {
+            values.push(value?);
+        }
+        match values.len() {
+            0 => return Err(Error::csv(None, "Line should contain at least one value")),
+            1 => {}
+            _ if options.format().trailing_bin() => {
+                values.pop();
+            }
+            _ => {}
+        }
+        return Ok(Some(line));
+    }
+
+    let line = match Line::parse(raw) {
+        Ok(line) => line,
+        Err(_) if parser::column_count(raw) <= 6 => {
+            report.drop(Problem::TooFewColumns);
+            return Ok(None);
+        }
+        Err(_) => {
+            report.drop(Problem::InvalidHeader);
+            return Ok(None);
+        }
+    };
+    let trailing = options.format().trailing_bin() as usize;
+    let expected = match line.bins() {
+        Some(bins) => bins + trailing,
+        None => {
+            report.drop(Problem::InvalidHeader);
+            return Ok(None);
+        }
+    };
+    let mut invalid = false;
+    for value in line.values() {
+        values.push(value.unwrap_or_else(|_| {
+            invalid = true;
+            f32::NAN
+        }));
+    }
+    if values.is_empty() {
+        report.drop(Problem::TooFewColumns);
+        return Ok(None);
+    }
+    if values.len() > expected {
+        report.drop(Problem::ExtraValues);
+        return Ok(None);
+    }
+    if (values.len() as f64) < expected as f64 * MIN_PRESENT {
+        report.drop(Problem::InvalidHeader);
+        return Ok(None);
+    }
+    if invalid {
+        report.repair(Problem::InvalidValue);
+    }
+    if values.len() < expected {
+        report.repair(Problem::MissingValues);
+        values.resize(expected, f32::NAN);
+    }
+    values.truncate(expected - trailing);
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &str, lenient: bool) -> (Result<Option<usize>>, Report) {
+        read_as(raw, lenient, InputFormat::RtlPower)
+    }
+
+    fn read_as(raw: &str, lenient: bool, format: InputFormat) -> (Result<Option<usize>>, Report) {
+        let mut values = Vec::new();
+        let mut report = Report::default();
+        let res = read_line(
+            raw.as_bytes(),
+            &ReadOptions {
+                lenient,
+                format: Some(format),
+                ..ReadOptions::default()
+            },
+            &mut values,
+            &mut report,
+        )
+        .map(|line| line.map(|_| values.len()));
+        (res, report)
+    }
+
+    #[test]
+    fn strict_fails() {
+        assert!(
+            read("2019-08-17, 22:37:25, 0, 4, 1, 1, 1, x, 3, 4, 5", false)
+                .0
+                .is_err()
+        );
+        assert!(matches!(
+            read("2019-08-17, 22:37:25, 0, 4", false).0,
+            Err(Error::Csv { .. })
+        ));
+        assert!(matches!(
+            read("2019-08-17, 22:37:25, 0, 4, 0, 1, 1, 2", false).0,
+            Err(Error::Geometry { .. })
+        ));
+    }
+
+    #[test]
+    fn lenient_valid() {
+        let (res, report) = read("2019-08-17, 22:37:25, 0, 4, 1, 1, 1, 2, 3, 4, 5", true);
+        assert_eq!(res.unwrap(), Some(4));
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn lenient_repairs() {
+        let (res, report) = read("2019-08-17, 22:37:25, 0, 4, 1, 1, 1, x, 3", true);
+        assert_eq!(res.unwrap(), Some(4));
+        assert_eq!(report.repaired.get(&Problem::InvalidValue), Some(&1));
+        assert_eq!(report.repaired.get(&Problem::MissingValues), Some(&1));
+    }
+
+    #[test]
+    fn lenient_drops_huge_headers() {
+        let raw = "2019-08-17, 22:37:01, 24000000, 900000000000, 0.5, 10, 1.0, 2.0";
+        let (res, report) = read(raw, true);
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(report.dropped.get(&Problem::InvalidHeader), Some(&1));
+    }
+
+    #[test]
+    fn hackrf_keeps_last_bin() {
+        let raw = "2019-08-17, 22:37:25.5, 0, 4, 1, 1, 1, 2, 3, 4";
+        for &lenient in &[false, true] {
+            let (res, report) = read_as(raw, lenient, InputFormat::HackrfSweep);
+            assert_eq!(res.unwrap(), Some(4));
+            assert!(report.is_empty());
+        }
+        let (res, _) = read_as(
+            "2019-08-17, 22:37:25.5, 0, 4, 1, 1, 1, 2, 3, 4, 5",
+            true,
+            InputFormat::HackrfSweep,
+        );
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[test]
+    fn lenient_drops() {
+        for (raw, problem) in &[
+            ("2019-08-17, 22:37:", Problem::TooFewColumns),
+            ("2019-08-17, 22:37:25, 0, 4, 1, 1", Problem::TooFewColumns),
+            (
+                "2019-08-17, 22:37:25, 0, x, 1, 1, 1, 2",
+                Problem::InvalidHeader,
+            ),
+            (
+                "2019-08-17, 22:37:25, 0, 4, 0, 1, 1, 2",
+                Problem::InvalidHeader,
+            ),
+            (
+                "2019-08-17, 22:37:25, 0, 4, 1, 1, 1, 2, 3, 4, 5, 6",
+                Problem::ExtraValues,
+            ),
+        ] {
+            let (res, report) = read(raw, true);
+            assert_eq!(res.unwrap(), None, "{}", raw);
+            assert_eq!(report.dropped.get(problem), Some(&1), "{}", raw);
+        }
+    }
+}
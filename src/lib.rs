@@ -1,17 +1,43 @@
 #![warn(clippy::unwrap_used)]
 use csv::StringRecord;
-use flate2::read::GzDecoder;
 use log::*;
 use std::f32;
 use std::io::prelude::*;
-use std::path::Path;
-use std::{cmp::Ordering, ffi::OsStr, fs::File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::{cmp::Ordering, fs::File};
+mod compression;
+mod concat;
+mod diff;
+mod error;
+mod formats;
+mod lenient;
 mod palettes;
-use anyhow::{Context, Result};
+pub mod parser;
+mod sigmf;
+mod sweep;
+#[cfg(test)]
+mod testing;
+mod timezone;
+mod waterfall;
 use arrayvec::ArrayVec;
+pub use compression::{Compression, EXTENSIONS};
+pub use concat::render_many;
+pub use diff::render_diff;
+pub use error::{Error, Result};
+use formats::head;
+pub use formats::{InputFormat, DETECT_LENGTH};
 use image::png::PngEncoder;
 use itertools::Itertools;
-pub use palettes::{scale_tocolor, Palette};
+pub use lenient::{Mismatch, Problem, ReadOptions, Report, WidthPolicy};
+use memmap2::Mmap;
+pub use palettes::{scale_tocolor, ColorScale, Palette, Transfer};
+use rayon::prelude::*;
+pub use sigmf::{save_metadata, write_metadata, Annotation, Extent, Metadata};
+pub use sweep::{db_to_power, mean_power, power_to_db, Hop, Overlap, Sweep};
+use sweep::{same_grid, Grouping, SweepBuilder};
+pub use timezone::{format_in, Clock, TimeBound, Tz};
+pub use waterfall::{Aggregation, Interpolation, Scale, Waterfall};
 
 #[derive(Debug)]
 struct Measurement {
@@ -20,63 +46,46 @@ struct Measurement {
     freq_low: u64,
     freq_high: u64,
     freq_step: f64,
+    #[allow(dead_code)]
     samples: u32,
     values: Vec<f32>,
 }
 
 impl Measurement {
-    fn get_values_with_freq(&self) -> Vec<(f64, f32)> {
-        self.values
-            .iter()
-            .zip(0..)
-            .map(|(value, i)| {
-                (
-                    ((i) as f64) * self.freq_step + (self.freq_low as f64),
-                    *value,
-                )
-            })
-            .collect()
-    }
     fn new(record: StringRecord) -> Result<Measurement> {
         let mut values: Vec<_> = record
             .iter()
+            .zip(1..)
             .skip(6)
-            .map(|s| parse_f32(s))
+            .map(|(s, column)| parse_f32(s, column))
             .collect::<Result<Vec<_>>>()?;
         if values.len() > 1 {
             values.remove(values.len() - 1);
         }
         Ok(Measurement {
-            date: record
-                .get(0)
-                .context("Couldn't get date column")?
-                .to_string(),
-            time: record
-                .get(1)
-                .context("Couldn't get time column")?
-                .to_string(),
-            freq_low: record
-                .get(2)
-                .context("Couldn't get freq_low column")?
-                .parse()?,
-            freq_high: record
-                .get(3)
-                .context("Couldn't get freq_high column")?
-                .parse()?,
-            freq_step: record
-                .get(4)
-                .context("Couldn't get freq_step column")?
-                .parse()?,
-            samples: record
-                .get(5)
-                .context("Couldn't get samples column")?
-                .parse()?,
+            date: get_column(&record, 0)?.to_string(),
+            time: get_column(&record, 1)?.to_string(),
+            freq_low: parse_column(&record, 2)?,
+            freq_high: parse_column(&record, 3)?,
+            freq_step: parse_column(&record, 4)?,
+            samples: parse_column(&record, 5)?,
             values,
         })
     }
 }
 
-#[derive(PartialEq, Debug)]
+fn get_column(record: &StringRecord, i: usize) -> Result<&str> {
+    record
+        .get(i)
+        .ok_or_else(|| Error::csv(Some(i + 1), "Missing column"))
+}
+
+fn parse_column<T: std::str::FromStr>(record: &StringRecord, i: usize) -> Result<T> {
+    let s = get_column(record, i)?;
+    s.parse().map_err(|_| Error::number(i + 1, s))
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Summary {
     pub min: f32,
     pub max: f32,
@@ -115,51 +124,426 @@ impl Summary {
             width,
         }
     }
+
+    /// Combines summaries of two consecutive parts of the same file, ignoring values that weren't set
+    fn merge(a: Self, b: Self) -> Self {
+        fn pick(a: f32, b: f32, f: fn(f32, f32) -> f32) -> f32 {
+            if !a.is_finite() {
+                b
+            } else if !b.is_finite() {
+                a
+            } else {
+                f(a, b)
+            }
+        }
+        Self {
+            min: pick(a.min, b.min, f32::min),
+            max: pick(a.max, b.max, f32::max),
+            width: if b.width > 0 { b.width } else { a.width },
+        }
+    }
+
+    /// Range of the values of a waterfall, such as after they were changed
+    fn of(waterfall: &Waterfall) -> Self {
+        waterfall
+            .values
+            .iter()
+            .filter(|value| value.is_finite())
+            .fold(Self::empty(), |sum, &val| {
+                Self::update(sum, val, waterfall.width)
+            })
+    }
+
+    fn update_sweep(a: Self, sweep: &mut Vec<f32>) -> Self {
+        let width = sweep.len();
+        sweep
+            .drain(..)
+            .fold(a, |sum, val| Self::update(sum, val, width))
+    }
 }
 
-fn parse_f32(s: &str) -> Result<f32> {
+fn parse_f32(s: &str, column: usize) -> Result<f32> {
     if s == "-nan" || s == "nan" {
         Ok(f32::NAN)
     } else {
-        Ok(s.parse::<f32>()?)
+        s.parse::<f32>().map_err(|_| Error::number(column, s))
+    }
+}
+
+/// Sets the position of errors about a csv record
+fn at_record(position: Option<&csv::Position>) -> impl FnOnce(Error) -> Error + '_ {
+    move |err| match position {
+        Some(position) => err.at(position.into()),
+        None => err,
     }
 }
 
 pub fn open_file<P: AsRef<Path>>(path: P) -> Result<Box<dyn std::io::Read>> {
     let path = path.as_ref();
-    let file = File::open(path).context(format!("Couldn't open file '{}'", path.display()))?;
-    match path.extension() {
-        Some(ext) if ext == OsStr::new("gz") => Ok(Box::new(GzDecoder::new(file))),
-        _ => Ok(Box::new(file)),
+    let mut reader = BufReader::new(File::open(path).map_err(|source| Error::Open {
+        path: path.to_path_buf(),
+        source,
+    })?);
+    let compression = Compression::sniff(&mut reader)?;
+    compression.decoder(reader)
+}
+
+/// Detects the compression of a file from its first bytes
+fn compression_of(path: &Path) -> Result<Compression> {
+    let file = File::open(path).map_err(|source| Error::Open {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Compression::sniff(&mut BufReader::new(file))
+}
+
+/// Memory-maps an uncompressed file, so it can be processed with [`preprocess_slice`] and [`process_slice`]
+pub fn map_file<P: AsRef<Path>>(path: P) -> Result<Mmap> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|source| Error::Open {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    // Safety: the file is only read, modifying it while it's being processed is undefined behavior,
+    // the same as with any other program that memory-maps its input
+    Ok(unsafe { Mmap::map(&file) }?)
+}
+
+/// Reads a whole stream, decompressing it if it starts with the magic bytes of a supported compression
+pub fn read_to_memory<R: Read>(mut reader: R) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    match Compression::detect(&data) {
+        Compression::None => Ok(data),
+        compression => {
+            let mut decompressed = Vec::new();
+            compression
+                .decoder(&data[..])?
+                .read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
     }
 }
 
-fn read_file<T: std::io::Read>(file: T) -> csv::Reader<T> {
+pub fn read_file<T: std::io::Read>(file: T) -> csv::Reader<T> {
     csv::ReaderBuilder::new()
         .has_headers(false)
         .from_reader(file)
 }
 
-pub fn main<P: AsRef<Path>>(path: P, palette: Palette) -> Result<()> {
+/// Where to read a capture from
+#[derive(Debug, Clone)]
+pub enum Input {
+    File(PathBuf),
+    /// Standard input, which is read to memory, so it can be processed twice
+    Stdin,
+}
+
+impl std::fmt::Display for Input {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Input::File(path) => write!(f, "file '{}'", path.display()),
+            Input::Stdin => f.write_str("standard input"),
+        }
+    }
+}
+
+/// Where to write an image to
+#[derive(Debug, Clone)]
+pub enum Output {
+    File(PathBuf),
+    Stdout,
+}
+
+/// Settings for turning the read values into an image
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    pub palette: Palette,
+    /// Write a `.sigmf-meta` file with these notes next to the image
+    pub metadata: Option<Metadata>,
+    /// When rendering several files into one image, insert a line where each of them starts
+    pub mark_boundaries: bool,
+    /// Stack sweeps in the order they were read, instead of adding empty rows for gaps in time
+    pub stack_rows: bool,
+    /// Time zone to give times in, such as in the metadata
+    pub timezone: Tz,
+    /// Combine bins so the waterfall is at most this many pixels wide
+    pub width: Option<usize>,
+    /// Combine rows so the waterfall, without the 26 rows of the frequency header, is at most this many pixels high
+    pub height: Option<usize>,
+    /// How to combine the values that end up in one pixel
+    pub aggregation: Aggregation,
+    /// Draw each value as a block of pixels, with the frequency header scaled to match
+    pub scale: Option<Scale>,
+    /// Render values relative to a per-bin baseline, so signals stand out from the noise floor and fixed spurs
+    pub baseline: Option<Baseline>,
+    /// Render each sweep relative to its own level, such as its median, so gain changes don't shift whole rows
+    pub sweep_level: Option<Aggregation>,
+    /// How values are spread over the palette
+    pub transfer: Transfer,
+}
+
+/// Where the level of each bin comes from, to render values relative to it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Baseline {
+    /// Median of the bin over time
+    Median,
+    /// Median of the bin in another capture of the same frequencies, such as one with the antenna disconnected
+    Reference(PathBuf),
+}
+
+/// Renders a capture into an image next to it, with a `.png` extension
+pub fn main<P: AsRef<Path>>(
+    path: P,
+    render_options: &RenderOptions,
+    options: &ReadOptions,
+) -> Result<()> {
     let path = path.as_ref();
-    info!("Loading: {}", path.display());
-    //Preprocess
-    let file = open_file(path)?;
-    let summary = preprocess_iter(file);
+    render(
+        &Input::File(path.to_path_buf()),
+        &Output::File(path.with_extension("png")),
+        render_options,
+        options,
+    )
+}
+
+pub fn render(
+    input: &Input,
+    output: &Output,
+    render_options: &RenderOptions,
+    options: &ReadOptions,
+) -> Result<()> {
+    let describe = render_options.metadata.is_some() && matches!(output, Output::File(_));
+    // A reference is checked against the capture's grid
+    let measure = describe || matches!(render_options.baseline, Some(Baseline::Reference(_)));
+    let (waterfall, report, extent) = match input {
+        Input::File(path) if compression_of(path)? != Compression::None => {
+            info!("Loading: {}", path.display());
+            let options = &detect_file_format(path, options)?;
+            let (waterfall, extent, report) =
+                Waterfall::read_with_extent(open_file(path)?, options)?;
+            (waterfall, report, measure.then_some(extent))
+        }
+        Input::File(path) => {
+            info!("Loading: {}", path.display());
+            let data = map_file(path)?;
+            let options = &detect_format(head(&data), options);
+            let (waterfall, report) = Waterfall::read_slice(&data, options)?;
+            let extent = if measure {
+                Some(Extent::read(&data[..], options)?)
+            } else {
+                None
+            };
+            (waterfall, report, extent)
+        }
+        Input::Stdin => {
+            info!("Loading: standard input");
+            let data = read_to_memory(std::io::stdin().lock())?;
+            let options = &detect_format(head(&data), options);
+            let (waterfall, report) = Waterfall::read_slice(&data, options)?;
+            let extent = if measure {
+                Some(Extent::read(&data[..], options)?)
+            } else {
+                None
+            };
+            (waterfall, report, extent)
+        }
+    };
+    if options.crops() && waterfall.values.is_empty() {
+        return Err(Error::EmptyRange);
+    }
+    let grid = extent.as_ref().map_or(&[][..], |extent| &extent.grid);
+    let waterfall = normalize(waterfall, grid, render_options, options)?;
+    let (waterfall, summary) = arrange(waterfall, render_options);
+    let img = paint(&waterfall, &summary, render_options);
+    write_output(
+        waterfall.width,
+        waterfall.height(),
+        img,
+        output,
+        render_options,
+        extent.filter(|_| describe),
+    )?;
+    report.log();
+    Ok(())
+}
+
+/// Subtracts the baseline and the level of each sweep, if requested.
+/// `grid` is the hops of the capture's first sweep, which a reference has to share.
+fn normalize(
+    mut waterfall: Waterfall,
+    grid: &[Hop],
+    render_options: &RenderOptions,
+    options: &ReadOptions,
+) -> Result<Waterfall> {
+    let baseline = match &render_options.baseline {
+        None => None,
+        Some(Baseline::Median) => Some(waterfall.baseline()),
+        Some(Baseline::Reference(path)) => Some(
+            reference_baseline(path, options, grid, waterfall.width).map_err(
+                |source| match source {
+                    Error::Reference { .. } => source,
+                    source => Error::InFile {
+                        path: path.clone(),
+                        source: Box::new(source),
+                    },
+                },
+            )?,
+        ),
+    };
+    if let Some(baseline) = baseline {
+        waterfall.subtract(&baseline);
+    }
+    if let Some(level) = render_options.sweep_level {
+        waterfall.level_rows(level);
+    }
+    Ok(waterfall)
+}
+
+/// Reads the baseline from a reference capture, with the same frequencies selected, but all of its times.
+/// Its bins have to be at the same frequencies as the capture's, whose first sweep has hops `grid`.
+fn reference_baseline(
+    path: &Path,
+    options: &ReadOptions,
+    grid: &[Hop],
+    width: usize,
+) -> Result<Vec<f32>> {
+    info!("Loading reference: {}", path.display());
+    let options = ReadOptions {
+        start: None,
+        end: None,
+        ..options.clone()
+    };
+    let options = detect_file_format(path, &options)?;
+    let (reference, extent, _) = Waterfall::read_with_extent(open_file(path)?, &options)?;
+    let problem = if reference.values.is_empty() {
+        Some("It contains no sweeps".to_string())
+    } else if reference.width != width {
+        Some(format!(
+            "It has {} bins, but the capture has {}",
+            reference.width, width
+        ))
+    } else if !same_grid(&extent.grid, grid) {
+        Some("Its bins are at different frequencies than the capture's".to_string())
+    } else {
+        None
+    };
+    match problem {
+        Some(message) => Err(Error::Reference {
+            path: path.to_path_buf(),
+            message,
+        }),
+        None => Ok(reference.baseline()),
+    }
+}
+
+/// Lays out the rows the way they are drawn, placed in time and resampled to the requested size.
+/// Returns the range of the values that are drawn: only the sweeps that were kept, after they
+/// were normalized, which is narrower once bins or sweeps are combined.
+fn arrange(mut waterfall: Waterfall, render_options: &RenderOptions) -> (Waterfall, Summary) {
+    if !render_options.stack_rows {
+        waterfall.place_in_time();
+    }
+    let waterfall = waterfall.downsample(
+        render_options.width,
+        render_options.height,
+        render_options.aggregation,
+    );
+    // Upsampling only repeats or interpolates values, so it keeps their range
+    let summary = Summary::of(&waterfall);
     info!("Color values {} to {}", summary.min, summary.max);
-    //Process
-    let file = open_file(path).context("Couldn't preprocess file")?;
-    let reader = read_file(file);
-    let (datawidth, dataheight, img) =
-        process(reader, summary.min, summary.max, palette).context("Couldn't process file")?;
-    //Draw
-    let (height, imgdata) = create_image(datawidth, dataheight, img);
-    let dest = path.with_extension("png");
-    save_image(datawidth, height, imgdata, dest)?;
+    match &render_options.scale {
+        Some(scale) => (waterfall.upsample(scale), summary),
+        None => (waterfall, summary),
+    }
+}
+
+/// Colors the waterfall, with a histogram of the values that are drawn if the transfer function needs one
+fn paint(waterfall: &Waterfall, summary: &Summary, render_options: &RenderOptions) -> Vec<u8> {
+    let scale = ColorScale::new(
+        render_options.transfer,
+        summary.min,
+        summary.max,
+        &waterfall.values,
+    );
+    waterfall.color_with(render_options.palette, &scale)
+}
+
+/// Draws the image and writes it, with metadata if requested
+fn write_output(
+    datawidth: usize,
+    dataheight: usize,
+    img: Vec<u8>,
+    output: &Output,
+    render_options: &RenderOptions,
+    extent: Option<Extent>,
+) -> Result<()> {
+    let header = HEADER_HEIGHT * render_options.scale.map_or(1, |scale| scale.y.max(1));
+    let (height, imgdata) = create_image(datawidth, dataheight, header, img);
+    match output {
+        Output::File(dest) => {
+            save_image(datawidth, height, imgdata, dest)?;
+            if let (Some(extent), Some(metadata)) = (extent, &render_options.metadata) {
+                save_metadata(dest, &extent, metadata, render_options.timezone)?;
+            }
+        }
+        Output::Stdout => {
+            info!("Writing to standard output {}x{}", datawidth, height);
+            let stdout = std::io::stdout();
+            let mut writer = BufWriter::new(stdout.lock());
+            write_image(datawidth, height, &imgdata, &mut writer)?;
+            writer.flush()?;
+            if render_options.metadata.is_some() {
+                warn!("Not writing metadata, because the image is written to standard output");
+            }
+        }
+    }
     Ok(())
 }
 
-pub fn preprocess(file: Box<dyn Read>) -> Summary {
+/// Reads the part of a file [`InputFormat::detect`] looks at, decompressed
+fn read_head(path: &Path) -> Result<Vec<u8>> {
+    Ok(formats::read_head(&mut open_file(path)?)?)
+}
+
+/// Reads the start of a file to detect its format, unless it was set
+fn detect_file_format(path: &Path, options: &ReadOptions) -> Result<ReadOptions> {
+    Ok(detect_format(&read_head(path)?, options))
+}
+
+/// Sets the input format, unless it was set already, and logs the decision. Also counts time offsets from the first sweep.
+fn detect_format(head: &[u8], options: &ReadOptions) -> ReadOptions {
+    let detected = InputFormat::detect(head);
+    let format = match (options.format, detected) {
+        (Some(set), Some(detected)) if set != detected => {
+            warn!(
+                "Input looks like {}, but it's read as {} as requested",
+                detected, set
+            );
+            set
+        }
+        (Some(set), _) => {
+            info!("Input format: {}", set);
+            set
+        }
+        (None, Some(detected)) => {
+            info!("Input format: {} (detected)", detected);
+            detected
+        }
+        (None, None) => {
+            let format = InputFormat::default();
+            info!("Couldn't detect the input format, reading it as {}", format);
+            format
+        }
+    };
+    ReadOptions {
+        format: Some(format),
+        ..options.clone()
+    }
+    .for_input(head)
+}
+
+pub fn preprocess(file: Box<dyn Read>) -> Result<Summary> {
     let reader = read_file(file);
     let mut min = f32::INFINITY;
     let mut max = f32::NEG_INFINITY;
@@ -167,7 +551,7 @@ pub fn preprocess(file: Box<dyn Read>) -> Summary {
     let mut first_date = None;
     for result in reader.into_records() {
         let record = {
-            let mut x = result.unwrap();
+            let mut x = result?;
             x.trim();
             x
         };
@@ -177,20 +561,18 @@ pub fn preprocess(file: Box<dyn Read>) -> Summary {
 
         let values: Vec<f32> = record
             .iter()
+            .zip(1..)
             .skip(6)
-            .map(|s| {
-                if s == "-nan" || s == "nan" {
-                    f32::NAN
-                } else {
-                    s.trim()
-                        .parse::<f32>()
-                        .unwrap_or_else(|e| panic!("'{}' should be a valid float: {:?}", s, e))
-                }
-            })
-            .collect();
+            .map(|(s, column)| parse_f32(s, column))
+            .collect::<Result<_>>()
+            .map_err(at_record(record.position()))?;
 
-        let values_count = values.len() - 1;
-        if first_date == None {
+        let values_count = values
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| Error::csv(None, "Line should contain at least one value"))
+            .map_err(at_record(record.position()))?;
+        if first_date.is_none() {
             first_date = timestamp;
             width = Some(values_count);
         } else if first_date == timestamp {
@@ -198,7 +580,7 @@ pub fn preprocess(file: Box<dyn Read>) -> Summary {
         }
 
         for value in values {
-            if value != f32::INFINITY && value != f32::NEG_INFINITY {
+            if !value.is_infinite() {
                 if value > max {
                     max = value
                 }
@@ -208,49 +590,91 @@ pub fn preprocess(file: Box<dyn Read>) -> Summary {
             }
         }
     }
-    Summary {
+    Ok(Summary {
         min,
         max,
-        width: width.unwrap(),
-    }
+        width: width.unwrap_or(0),
+    })
 }
 
-pub fn preprocess_iter(file: Box<dyn Read>) -> Summary {
-    read_file(file)
-        .into_records()
-        .map(|x| {
-            let mut x = x.unwrap();
-            x.trim();
-            x
-        })
-        .group_by(|line| format!("{} {}", line.get(0).unwrap(), line.get(1).unwrap()))
-        .into_iter()
-        .map(|(_, group)| {
-            group
-                .flat_map(|line| {
-                    let mut vals = line
-                        .into_iter()
-                        .skip(6)
-                        .map(|s| {
-                            if s == "-nan" || s == "nan" {
-                                f32::NAN
-                            } else {
-                                s.parse::<f32>().unwrap_or_else(|e| {
-                                    panic!("'{}' should be a valid float: '{:?}'", s, e)
-                                })
-                            }
-                        })
-                        .collect::<Vec<f32>>();
-                    vals.pop().unwrap();
-                    vals
-                })
-                .collect::<Vec<_>>()
-        })
-        .fold(Summary::empty(), |sum, vals| {
-            let width = vals.len();
-            vals.into_iter()
-                .fold(sum, |sum, val| Summary::update(sum, val, width))
+/// Timestamp and values of a line, without the extra trailing bin
+fn timestamp_and_values(record: &StringRecord) -> Result<(String, Vec<f32>)> {
+    let timestamp = format!("{} {}", get_column(record, 0)?, get_column(record, 1)?);
+    let mut values = record
+        .iter()
+        .zip(1..)
+        .skip(6)
+        .map(|(s, column)| parse_f32(s, column))
+        .collect::<Result<Vec<f32>>>()?;
+    values
+        .pop()
+        .ok_or_else(|| Error::csv(None, "Line should contain at least one value"))?;
+    Ok((timestamp, values))
+}
+
+pub fn preprocess_iter(file: Box<dyn Read>) -> Result<Summary> {
+    let lines = read_file(file).into_records().map(|x| {
+        let mut x = x?;
+        x.trim();
+        timestamp_and_values(&x).map_err(at_record(x.position()))
+    });
+    itertools::process_results(lines, |lines| {
+        lines
+            .group_by(|(timestamp, _)| timestamp.clone())
+            .into_iter()
+            .map(|(_, group)| group.flat_map(|(_, vals)| vals).collect::<Vec<_>>())
+            .fold(Summary::empty(), |sum, vals| {
+                let width = vals.len();
+                vals.into_iter()
+                    .fold(sum, |sum, val| Summary::update(sum, val, width))
+            })
+    })
+}
+
+/// Same as [`preprocess_iter`], but uses a specialized parser instead of the `csv` crate
+pub fn preprocess_fast<R: Read>(file: R, options: &ReadOptions) -> Result<(Summary, Report)> {
+    let mut summary = Summary::empty();
+    let mut report = Report::default();
+    let (options, reader) = formats::read_start(file, options)?;
+    let options = &options;
+    formats::for_each_sweep(reader, options, &mut report, |mut sweep| {
+        summary = Summary::update_sweep(
+            std::mem::replace(&mut summary, Summary::empty()),
+            &mut sweep.values,
+        );
+        Ok(())
+    })?;
+    Ok((summary, report))
+}
+
+/// Same as [`preprocess_fast`], but works on an in-memory buffer in parallel
+pub fn preprocess_slice(data: &[u8], options: &ReadOptions) -> Result<(Summary, Report)> {
+    let options = &options.for_input(head(data));
+    if options.start.is_some() || options.end.is_some() {
+        // Which sweeps are in the selected times is only known once they are all read in order
+        let (waterfall, report) = Waterfall::read_slice(data, options)?;
+        return Ok((Summary::of(&waterfall), report));
+    }
+    options
+        .format()
+        .split(data, rayon::current_num_threads())
+        .into_par_iter()
+        .map(|chunk| {
+            let mut summary = Summary::empty();
+            let mut report = Report::default();
+            formats::for_each_sweep_in(data, chunk, options, &mut report, |mut sweep| {
+                summary = Summary::update_sweep(
+                    std::mem::replace(&mut summary, Summary::empty()),
+                    &mut sweep.values,
+                );
+                Ok(())
+            })
+            .map(|_| (summary, report))
         })
+        .try_reduce(
+            || (Summary::empty(), Report::default()),
+            |a, b| Ok((Summary::merge(a.0, b.0), Report::merge(a.1, b.1))),
+        )
 }
 
 pub fn process<R: Read>(
@@ -259,40 +683,63 @@ pub fn process<R: Read>(
     max: f32,
     palette: Palette,
 ) -> Result<(usize, usize, std::vec::Vec<u8>)> {
-    let mut date: String = "".to_string();
-    let mut time: String = "".to_string();
-    let mut batch = 0;
-    let mut datawidth = 0;
-    let mut img = Vec::new();
+    let mut builder = SweepBuilder::new(Grouping::Timestamp);
+    let mut sweeps = Vec::new();
     for result in reader.into_records() {
         let mut record = result?;
         record.trim();
-        assert!(record.len() >= 7);
-        let m = Measurement::new(record)?;
-        let vals = m.get_values_with_freq();
-        if date == m.date && time == m.time {
-        } else {
-            if datawidth == 0 {
-                datawidth = batch;
-            }
-            debug_assert_eq! {datawidth,batch}
-            batch = 0;
-            date = m.date;
-            time = m.time;
-        }
-        for (_, v) in vals {
-            let pixel = scale_tocolor(palette, v, min, max);
-            img.extend(pixel.iter());
-            batch += 1;
+        let position = record.position().cloned();
+        if record.len() < 7 {
+            let message = format!("Expected at least 7 columns, found {}", record.len());
+            return Err(at_record(position.as_ref())(Error::csv(None, message)));
         }
+        let m = Measurement::new(record).map_err(at_record(position.as_ref()))?;
+        let hop = Hop {
+            freq_low: m.freq_low,
+            freq_high: m.freq_high,
+            freq_step: m.freq_step,
+            start: 0,
+        };
+        sweeps.extend(builder.push_hop(&m.date, &m.time, hop, &m.values));
     }
-    if datawidth == 0 {
-        datawidth = batch;
-    }
-    let w = datawidth;
-    let h = img.len() / 3 / datawidth;
-    info!("Img data {}x{}", w, h);
-    Ok((w, h, img))
+    sweeps.extend(builder.finish());
+    let options = ReadOptions::default();
+    let mut report = Report::default();
+    let sweeps = sweeps
+        .into_iter()
+        .map(|sweep| sweep.linearize(options.overlap))
+        .collect::<Result<Vec<_>>>()?;
+    let waterfall = Waterfall::from_sweeps(sweeps, &options, &mut report);
+    report.log();
+    info!("Img data {}x{}", waterfall.width, waterfall.height());
+    let img = waterfall.color(palette, min, max);
+    Ok((waterfall.width, waterfall.height(), img))
+}
+
+/// Same as [`process`], but uses a specialized parser instead of the `csv` crate
+pub fn process_fast<R: Read>(
+    file: R,
+    min: f32,
+    max: f32,
+    palette: Palette,
+    options: &ReadOptions,
+) -> Result<(usize, usize, std::vec::Vec<u8>)> {
+    let (waterfall, _) = Waterfall::read(file, options)?;
+    let img = waterfall.color(palette, min, max);
+    Ok((waterfall.width, waterfall.height(), img))
+}
+
+/// Same as [`process_fast`], but works on an in-memory buffer in parallel
+pub fn process_slice(
+    data: &[u8],
+    min: f32,
+    max: f32,
+    palette: Palette,
+    options: &ReadOptions,
+) -> Result<(usize, usize, std::vec::Vec<u8>)> {
+    let (waterfall, _) = Waterfall::read_slice(data, options)?;
+    let img = waterfall.color(palette, min, max);
+    Ok((waterfall.width, waterfall.height(), img))
 }
 
 pub fn process_iter<R: Read>(
@@ -310,7 +757,7 @@ pub fn process_iter<R: Read>(
             record
         })
         .map(Measurement::new)
-        .flat_map(|m| m.unwrap().values.into_iter())
+        .flat_map(|m| m.expect("Invalid measurement").values.into_iter())
         .flat_map(|val| {
             let slice = scale_tocolor(Palette::Default, val, min, max);
             ArrayVec::from(slice).into_iter()
@@ -320,17 +767,25 @@ pub fn process_iter<R: Read>(
     (width, img.len() / 3 / width, img)
 }
 
-fn tape_measure(width: usize, imgdata: &mut Vec<u8>) {
-    let length = width * 26 * 3;
+/// Rows of the frequency header above the waterfall, at the original scale
+const HEADER_HEIGHT: usize = 26;
+
+fn tape_measure(width: usize, height: usize, imgdata: &mut Vec<u8>) {
+    let length = width * height * 3;
     imgdata.append(&mut vec![0; length]);
 }
 
-fn create_image(width: usize, height: usize, mut img: Vec<u8>) -> (usize, std::vec::Vec<u8>) {
+fn create_image(
+    width: usize,
+    height: usize,
+    header: usize,
+    mut img: Vec<u8>,
+) -> (usize, std::vec::Vec<u8>) {
     info!("Raw {}x{}", width, height);
     let mut imgdata: Vec<u8> = Vec::new();
-    tape_measure(width, &mut imgdata);
+    tape_measure(width, header, &mut imgdata);
     imgdata.append(&mut img);
-    let height = height + 26;
+    let height = height + header;
     let expected_length = width * height * 3;
     match expected_length.cmp(&imgdata.len()) {
         Ordering::Greater => {
@@ -353,13 +808,15 @@ fn save_image<P: std::convert::AsRef<std::path::Path>>(
     dest: P,
 ) -> Result<()> {
     info!("Saving {} {}x{}", dest.as_ref().display(), width, height);
-    let f = std::fs::File::create(dest)?;
-    PngEncoder::new(f).encode(
-        &imgdata,
-        width as u32,
-        height as u32,
-        image::ColorType::Rgb8,
-    )?;
+    let f = std::fs::File::create(&dest).map_err(|source| Error::Create {
+        path: dest.as_ref().to_path_buf(),
+        source,
+    })?;
+    write_image(width, height, &imgdata, BufWriter::new(f))
+}
+
+fn write_image<W: Write>(width: usize, height: usize, imgdata: &[u8], writer: W) -> Result<()> {
+    PngEncoder::new(writer).encode(imgdata, width as u32, height as u32, image::ColorType::Rgb8)?;
     Ok(())
 }
 
@@ -371,7 +828,7 @@ mod tests {
 
     #[test]
     fn preprocess_basic_result() {
-        let res = preprocess(open_file(Path::new("samples/46M.csv.gz")).unwrap());
+        let res = preprocess(open_file(Path::new("samples/46M.csv.gz")).unwrap()).unwrap();
         assert_eq!(
             res,
             Summary {
@@ -382,6 +839,119 @@ mod tests {
         );
     }
 
+    #[test]
+    fn preprocess_errors() {
+        let data = "2019-08-17, 22:37:25, 1, 2, 3, 4, 5.0, 6.0\n\
+                    2019-08-17, 22:37:25, 1, 2, 3, 4, 5.0, abc\n";
+        let expected = "Invalid number 'abc' on line 2, byte 43, column 8";
+        let err = preprocess(Box::new(data.as_bytes())).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        let err = preprocess_iter(Box::new(data.as_bytes())).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        let err = preprocess_fast(data.as_bytes(), &ReadOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        let err = preprocess_slice(data.as_bytes(), &ReadOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn lenient_report() {
+        let data = "2019-08-17, 22:37:25, 0, 4, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0\n\
+                    garbage\n\
+                    2019-08-17, 22:37:35, 0, 4, 1, 1, 1.0, x, 3.0, 4.0, 5.0\n\
+                    2019-08-17, 22:37:45, 0, 4, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0\n\
+                    2019-08-17, 22:37:55, 0, 4, 1, 1, -1.0, 2.0, 3.";
+        let options = ReadOptions {
+            lenient: true,
+            ..ReadOptions::default()
+        };
+        assert!(preprocess_fast(data.as_bytes(), &ReadOptions::default()).is_err());
+        let (summary, report) = preprocess_fast(data.as_bytes(), &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                min: -1.0,
+                max: 4.0,
+                width: 4
+            }
+        );
+        assert_eq!(report.dropped.get(&Problem::TooFewColumns), Some(&1));
+        assert_eq!(report.dropped.get(&Problem::ExtraValues), Some(&1));
+        assert_eq!(report.repaired.get(&Problem::InvalidValue), Some(&1));
+        assert_eq!(report.repaired.get(&Problem::MissingValues), Some(&1));
+        assert_eq!(
+            preprocess_slice(data.as_bytes(), &options).unwrap(),
+            (summary, report)
+        );
+
+        let fast = process_fast(data.as_bytes(), -1.0, 4.0, Palette::Default, &options).unwrap();
+        let slice = process_slice(data.as_bytes(), -1.0, 4.0, Palette::Default, &options).unwrap();
+        assert_eq!((fast.0, fast.1), (4, 3));
+        assert_eq!(fast, slice);
+    }
+
+    #[test]
+    fn open_renamed() {
+        let data = b"2019-08-17, 22:37:25, 0, 4, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0\n";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("renamed");
+        std::fs::write(&path, zstd::encode_all(&data[..], 1).unwrap()).unwrap();
+        let mut read = Vec::new();
+        open_file(&path).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data.to_vec());
+        assert_eq!(compression_of(&path).unwrap(), Compression::Zstd);
+    }
+
+    #[test]
+    fn open_errors() {
+        assert!(matches!(
+            open_file("samples/missing.csv"),
+            Err(Error::Open { .. })
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corrupt.csv.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&[b'0'; 1000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        std::fs::write(&path, &compressed[..compressed.len() / 2]).unwrap();
+        let err = preprocess_fast(open_file(&path).unwrap(), &ReadOptions::default());
+        assert!(matches!(err, Err(Error::Decompression(_))));
+    }
+
+    #[test]
+    fn rejects_offset_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, freq_low: u32| {
+            let path = dir.path().join(name);
+            let data = format!(
+                "2019-08-17, 22:37:25, {}, {}, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0\n",
+                freq_low,
+                freq_low + 4
+            );
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        let capture = write("capture.csv", 0);
+        let render_with = |reference: PathBuf| {
+            let render_options = RenderOptions {
+                baseline: Some(Baseline::Reference(reference)),
+                ..RenderOptions::default()
+            };
+            render(
+                &Input::File(capture.clone()),
+                &Output::File(capture.with_extension("png")),
+                &render_options,
+                &ReadOptions::default(),
+            )
+        };
+        render_with(write("same.csv", 0)).unwrap();
+        let offset = write("offset.csv", 10);
+        match render_with(offset.clone()) {
+            Err(Error::Reference { path, .. }) => assert_eq!(path, offset),
+            other => panic!("expected a reference error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn webp_new_image() {
         let size =
@@ -391,7 +961,7 @@ mod tests {
 
     #[test]
     fn preprocess_iter_result() {
-        let res = preprocess_iter(open_file(Path::new("samples/46M.csv.gz")).unwrap());
+        let res = preprocess_iter(open_file(Path::new("samples/46M.csv.gz")).unwrap()).unwrap();
         assert_eq!(
             res,
             Summary {
@@ -402,9 +972,228 @@ mod tests {
         );
     }
 
+    #[test]
+    fn preprocess_fast_result() {
+        let res = preprocess_fast(
+            open_file(Path::new("samples/46M.csv.gz")).unwrap(),
+            &ReadOptions::default(),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(
+            res,
+            Summary {
+                min: -29.4,
+                max: 21.35,
+                width: 11622,
+            }
+        );
+    }
+
+    #[test_resources("samples/*.csv*")]
+    fn preprocess_implementations_equal(path: &str) {
+        let iter = preprocess_iter(open_file(path).unwrap()).unwrap();
+        let fast = preprocess_fast(open_file(path).unwrap(), &ReadOptions::default())
+            .unwrap()
+            .0;
+        assert_eq!(iter, fast);
+    }
+
+    #[test_resources("samples/*.csv*")]
+    fn process_fast_equal(path: &str) {
+        let sum = preprocess_iter(open_file(path).unwrap()).unwrap();
+        let basic = process(
+            read_file(open_file(path).unwrap()),
+            sum.min,
+            sum.max,
+            Palette::Default,
+        )
+        .unwrap();
+        let fast = process_fast(
+            open_file(path).unwrap(),
+            sum.min,
+            sum.max,
+            Palette::Default,
+            &ReadOptions::default(),
+        )
+        .unwrap();
+
+        assert!(basic.2 == fast.2, "Results differ");
+        assert_eq!(basic.0, fast.0, "Widths differ");
+        assert_eq!(basic.1, fast.1, "Heights differ");
+    }
+
+    #[test]
+    fn process_checks_widths() {
+        // The second sweep has a hop more than the others
+        let data = "2019-08-17, 22:37:25, 0, 2, 1, 1, 1.0, 2.0, 0\n\
+                    2019-08-17, 22:37:35, 0, 2, 1, 1, 1.0, 2.0, 0\n\
+                    2019-08-17, 22:37:35, 2, 4, 1, 1, 3.0, 3.0, 0\n\
+                    2019-08-17, 22:37:45, 0, 2, 1, 1, 1.0, 2.0, 0\n";
+        let (w, h, img) = process(read_file(data.as_bytes()), 0.0, 3.0, Palette::Default).unwrap();
+        assert_eq!((w, h, img.len()), (2, 3, 2 * 3 * 3));
+        let (w, h, img) = process(read_file(&b""[..]), 0.0, 3.0, Palette::Default).unwrap();
+        assert_eq!((w, h, img.len()), (0, 0, 0));
+    }
+
+    #[test]
+    fn arrange_narrows_summary() {
+        let waterfall = Waterfall {
+            width: 2,
+            values: vec![-10.0, -10.0, -30.0, -30.0],
+            times: vec![None, None],
+            boundaries: Vec::new(),
+        };
+        let (_, unchanged) = arrange(waterfall.clone(), &RenderOptions::default());
+        assert_eq!(unchanged, Summary::of(&waterfall));
+
+        let render_options = RenderOptions {
+            height: Some(1),
+            aggregation: Aggregation::Max,
+            ..RenderOptions::default()
+        };
+        let (combined, summary) = arrange(waterfall, &render_options);
+        assert_eq!(combined.values, vec![-10.0, -10.0]);
+        assert_eq!((summary.min, summary.max), (-10.0, -10.0));
+    }
+
+    #[test]
+    fn arrange_leaves_out_dropped_sweeps() {
+        let data = "2019-08-17, 22:37:25, 0, 2, 1, 1, 1.0, 2.0, 0\n\
+                    2019-08-17, 22:37:35, 0, 3, 1, 1, 90.0, 90.0, 90.0, 0\n\
+                    2019-08-17, 22:37:45, 0, 2, 1, 1, 3.0, 4.0, 0\n";
+        let options = ReadOptions {
+            mismatch: WidthPolicy::Drop,
+            ..ReadOptions::default()
+        };
+        let (waterfall, _) = Waterfall::read_slice(data.as_bytes(), &options).unwrap();
+        let (_, summary) = arrange(waterfall, &RenderOptions::default());
+        assert_eq!((summary.min, summary.max), (1.0, 4.0));
+    }
+
+    #[test]
+    fn fast_matches_csv_inline() {
+        let data = "2019-08-17, 22:37:25, 24000000, 24010000, 2500.00, 2, -1.5, 2.25, -nan, 7.0, 0.5\n\
+                    2019-08-17, 22:37:25, 24010000, 24020000, 2500.00, 2, 3.0, nan, 1.0, -4.0, 0.5\n\
+                    2019-08-17, 22:37:35, 24000000, 24010000, 2500.00, 2, -2.5, 1.25, 4.0, 6.0, 0.5\n\
+                    2019-08-17, 22:37:35, 24010000, 24020000, 2500.00, 2, 3.5, 0.0, 1.0, -4.5, 0.5\n";
+        let iter = preprocess_iter(Box::new(data.as_bytes())).unwrap();
+        let fast = preprocess_fast(data.as_bytes(), &ReadOptions::default())
+            .unwrap()
+            .0;
+        assert_eq!(iter, fast);
+        assert_eq!(fast.width, 8);
+        let basic = process(
+            read_file(data.as_bytes()),
+            fast.min,
+            fast.max,
+            Palette::Default,
+        )
+        .unwrap();
+        let slice = process_slice(
+            data.as_bytes(),
+            fast.min,
+            fast.max,
+            Palette::Default,
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            preprocess_slice(data.as_bytes(), &ReadOptions::default())
+                .unwrap()
+                .0,
+            fast
+        );
+        let fast = process_fast(
+            data.as_bytes(),
+            fast.min,
+            fast.max,
+            Palette::Default,
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(basic, fast);
+        assert_eq!(fast, slice);
+    }
+
+    fn read_sample(path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        open_file(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test_resources("samples/*.csv.gz")]
+    fn read_to_memory_decompresses(path: &str) {
+        let file = File::open(path).unwrap();
+        assert_eq!(read_to_memory(file).unwrap(), read_sample(path));
+    }
+
+    #[test]
+    fn read_to_memory_sniffs_gzip() {
+        let data = b"2019-08-17, 22:37:25, 0, 4, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(read_to_memory(&compressed[..]).unwrap(), data.to_vec());
+        assert_eq!(read_to_memory(&data[..]).unwrap(), data.to_vec());
+        assert!(matches!(
+            read_to_memory(&compressed[..10]),
+            Err(Error::Decompression(_))
+        ));
+    }
+
+    #[test]
+    fn write_png() {
+        let data = "2019-08-17, 22:37:25, 0, 4, 1, 1, 1.0, 2.0, 3.0, 4.0, 5.0\n\
+                    2019-08-17, 22:37:35, 0, 4, 1, 1, 2.0, 3.0, 4.0, 5.0, 6.0\n";
+        let (waterfall, _) =
+            Waterfall::read_slice(data.as_bytes(), &ReadOptions::default()).unwrap();
+        let summary = Summary::of(&waterfall);
+        let (w, h) = (waterfall.width, waterfall.height());
+        let img = waterfall.color(Palette::Default, summary.min, summary.max);
+        let (h, img) = create_image(w, h, HEADER_HEIGHT, img);
+        let mut png = Vec::new();
+        write_image(w, h, &img, &mut png).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (4, 28));
+        assert_eq!(decoded.into_raw(), img);
+    }
+
+    #[test_resources("samples/*.csv*")]
+    fn slice_implementations_equal(path: &str) {
+        let data = read_sample(path);
+        let fast = preprocess_fast(&data[..], &ReadOptions::default())
+            .unwrap()
+            .0;
+        let slice = preprocess_slice(&data, &ReadOptions::default()).unwrap().0;
+        assert_eq!(fast, slice);
+        let fast = process_fast(
+            &data[..],
+            fast.min,
+            fast.max,
+            Palette::Default,
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        let slice = process_slice(
+            &data,
+            slice.min,
+            slice.max,
+            Palette::Default,
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        assert!(fast == slice, "Results differ");
+    }
+
+    #[test_resources("samples/*.csv")]
+    fn map_file_equal(path: &str) {
+        assert_eq!(&map_file(path).unwrap()[..], &read_sample(path)[..]);
+    }
+
     #[test_resources("samples/*.csv.gz")]
     fn process_implementations_equal(path: &str) {
-        let sum = preprocess_iter(open_file(path).unwrap());
+        let sum = preprocess_iter(open_file(path).unwrap()).unwrap();
         let basic = process(
             read_file(open_file(path).unwrap()),
             sum.min,
@@ -426,12 +1215,12 @@ mod tests {
 
     #[test_resources("samples/*.csv.gz")]
     fn complete_gzip(path: &str) {
-        main(path, Palette::Default).unwrap()
+        main(path, &RenderOptions::default(), &ReadOptions::default()).unwrap()
     }
 
     #[test_resources("samples/*.csv")]
     fn complete_plain(path: &str) {
-        main(path, Palette::Default).unwrap()
+        main(path, &RenderOptions::default(), &ReadOptions::default()).unwrap()
     }
 
     #[test]
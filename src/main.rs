@@ -1,9 +1,16 @@
 #![warn(clippy::unwrap_used)]
 use anyhow::Result;
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, Utc};
 use log::{debug, warn};
-use sdr_heatmap::Palette;
-use std::{path::PathBuf, str::FromStr};
+use sdr_heatmap::{
+    Aggregation, Annotation, Baseline, Clock, Input, InputFormat, Interpolation, Metadata, Output,
+    Overlap, Palette, ReadOptions, RenderOptions, Scale, TimeBound, Transfer, Tz, WidthPolicy,
+};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use walkdir::WalkDir;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -16,6 +23,7 @@ use structopt::StructOpt;
 enum OptPalette {
     Default,
     Extended,
+    Diverging,
 }
 
 impl FromStr for OptPalette {
@@ -25,20 +33,320 @@ impl FromStr for OptPalette {
         match s {
             "default" => Ok(OptPalette::Default),
             "extended" => Ok(OptPalette::Extended),
+            "diverging" => Ok(OptPalette::Diverging),
             _ => Err(anyhow!("{} is not a valid palette name", s)),
         }
     }
 }
-impl Into<Palette> for OptPalette {
-    fn into(self) -> Palette {
-        match self {
+impl From<OptPalette> for Palette {
+    fn from(palette: OptPalette) -> Self {
+        match palette {
             OptPalette::Default => Palette::Default,
             OptPalette::Extended => Palette::Extended,
+            OptPalette::Diverging => Palette::Diverging,
         }
     }
 }
 
 #[derive(Debug, StructOpt)]
+enum OptInputFormat {
+    RtlPower,
+    HackrfSweep,
+    SoapyPower,
+    SoapyPowerBinary,
+    RtlPowerFftw,
+}
+
+impl FromStr for OptInputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rtl_power" => Ok(OptInputFormat::RtlPower),
+            "hackrf_sweep" => Ok(OptInputFormat::HackrfSweep),
+            "soapy_power" => Ok(OptInputFormat::SoapyPower),
+            "soapy_power_bin" => Ok(OptInputFormat::SoapyPowerBinary),
+            "rtl_power_fftw" => Ok(OptInputFormat::RtlPowerFftw),
+            _ => Err(anyhow!("{} is not a valid input format", s)),
+        }
+    }
+}
+impl From<OptInputFormat> for InputFormat {
+    fn from(format: OptInputFormat) -> Self {
+        match format {
+            OptInputFormat::RtlPower => InputFormat::RtlPower,
+            OptInputFormat::HackrfSweep => InputFormat::HackrfSweep,
+            OptInputFormat::SoapyPower => InputFormat::SoapyPower,
+            OptInputFormat::SoapyPowerBinary => InputFormat::SoapyPowerBinary,
+            OptInputFormat::RtlPowerFftw => InputFormat::RtlPowerFftw,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum OptOverlap {
+    Average,
+    Max,
+    Center,
+}
+
+impl FromStr for OptOverlap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "average" => Ok(OptOverlap::Average),
+            "max" => Ok(OptOverlap::Max),
+            "center" => Ok(OptOverlap::Center),
+            _ => Err(anyhow!("{} is not a valid overlap mode", s)),
+        }
+    }
+}
+impl From<OptOverlap> for Overlap {
+    fn from(overlap: OptOverlap) -> Self {
+        match overlap {
+            OptOverlap::Average => Overlap::Average,
+            OptOverlap::Max => Overlap::Max,
+            OptOverlap::Center => Overlap::PreferCenter,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum OptWidthPolicy {
+    Pad,
+    Drop,
+}
+
+impl FromStr for OptWidthPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pad" => Ok(OptWidthPolicy::Pad),
+            "drop" => Ok(OptWidthPolicy::Drop),
+            _ => Err(anyhow!("{} is not a valid width mismatch policy", s)),
+        }
+    }
+}
+impl From<OptWidthPolicy> for WidthPolicy {
+    fn from(policy: OptWidthPolicy) -> Self {
+        match policy {
+            OptWidthPolicy::Pad => WidthPolicy::Pad,
+            OptWidthPolicy::Drop => WidthPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OptAggregation(Aggregation);
+
+impl FromStr for OptAggregation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let aggregation = match s {
+            "mean" => Aggregation::Mean,
+            "max" => Aggregation::Max,
+            "min" => Aggregation::Min,
+            "median" => Aggregation::Percentile(50.0),
+            _ => match s.strip_prefix('p').map(str::parse::<f32>) {
+                Some(Ok(percentile)) if (0.0..=100.0).contains(&percentile) => {
+                    Aggregation::Percentile(percentile)
+                }
+                _ => bail!("{} is not a valid aggregation", s),
+            },
+        };
+        Ok(OptAggregation(aggregation))
+    }
+}
+
+#[derive(Debug)]
+enum OptInterpolation {
+    Nearest,
+    Linear,
+}
+
+impl FromStr for OptInterpolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(OptInterpolation::Nearest),
+            "linear" => Ok(OptInterpolation::Linear),
+            _ => Err(anyhow!("{} is not a valid interpolation", s)),
+        }
+    }
+}
+impl From<OptInterpolation> for Interpolation {
+    fn from(interpolation: OptInterpolation) -> Self {
+        match interpolation {
+            OptInterpolation::Nearest => Interpolation::Nearest,
+            OptInterpolation::Linear => Interpolation::Linear,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OptTransfer(Transfer);
+
+impl FromStr for OptTransfer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameter) = match s.split_once(':') {
+            Some((name, parameter)) => (
+                name,
+                Some(
+                    parameter
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|parameter| parameter.is_finite() && *parameter > 0.0)
+                        .ok_or_else(|| {
+                            anyhow!("{} is not a valid parameter of {}", parameter, name)
+                        })?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let transfer = match (name, parameter) {
+            ("linear", None) => Transfer::Linear,
+            ("equalize", None) => Transfer::Equalize,
+            ("gamma", parameter) => Transfer::Gamma(parameter.unwrap_or(0.5)),
+            ("sigmoid", parameter) => Transfer::Sigmoid(parameter.unwrap_or(10.0)),
+            _ => bail!("{} is not a valid transfer function", s),
+        };
+        Ok(OptTransfer(transfer))
+    }
+}
+
+/// A time as given on the command line, before the time zone of the input is known
+#[derive(Debug, Clone, Copy)]
+enum OptTime {
+    /// With an offset, such as 2021-10-31T01:30:00+01:00 or 2021-10-31T00:30:00Z
+    Absolute(DateTime<FixedOffset>),
+    /// In the time zone of the input, such as 2021-10-31 01:30:00
+    Local(NaiveDateTime),
+    /// Since the first sweep, such as +1h30m
+    Offset(Duration),
+}
+
+impl FromStr for OptTime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            anyhow!(
+                "{} is not a valid time, expected a date and time, such as '2021-10-31 01:30', or an offset, such as +1h30m",
+                s
+            )
+        };
+        if let Some(offset) = s.strip_prefix('+') {
+            let mut duration = Duration::zero();
+            let mut number = String::new();
+            for c in offset.chars() {
+                if c.is_ascii_digit() || c == '.' {
+                    number.push(c);
+                    continue;
+                }
+                let seconds = match c {
+                    'd' => 86400.0,
+                    'h' => 3600.0,
+                    'm' => 60.0,
+                    's' => 1.0,
+                    _ => return Err(error()),
+                };
+                let value: f64 = number.parse().map_err(|_| error())?;
+                duration += Duration::milliseconds((value * seconds * 1000.0) as i64);
+                number.clear();
+            }
+            if !number.is_empty() || offset.is_empty() {
+                return Err(error());
+            }
+            return Ok(OptTime::Offset(duration));
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(OptTime::Absolute(time));
+        }
+        [
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .map(OptTime::Local)
+        .ok_or_else(error)
+    }
+}
+
+impl OptTime {
+    fn bound(self, zone: Tz) -> Result<TimeBound> {
+        Ok(match self {
+            OptTime::Absolute(time) => TimeBound::At(time.with_timezone(&Utc)),
+            OptTime::Local(time) => TimeBound::At(
+                Clock::new(zone)
+                    .instant(time)
+                    .ok_or_else(|| anyhow!("{} doesn't exist in {}", time, zone))?,
+            ),
+            OptTime::Offset(offset) => TimeBound::After(offset),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct OptAnnotation(Annotation);
+
+impl FromStr for OptAnnotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || anyhow!("{} is not a valid annotation, expected LOW-HIGH=LABEL", s);
+        let (range, label) = s.split_once('=').ok_or_else(error)?;
+        let (low, high) = range.split_once('-').ok_or_else(error)?;
+        Ok(OptAnnotation(Annotation {
+            freq_low: low.trim().parse().map_err(|_| error())?,
+            freq_high: high.trim().parse().map_err(|_| error())?,
+            label: label.to_string(),
+        }))
+    }
+}
+
+/// Parses a frequency in Hz, optionally with a k, M or G suffix, such as 433.05M
+fn parse_frequency(s: &str) -> Result<f64> {
+    let (number, multiplier) = match s.trim().char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s.trim()[..i], 1e3),
+        Some((i, 'M')) => (&s.trim()[..i], 1e6),
+        Some((i, 'G')) | Some((i, 'g')) => (&s.trim()[..i], 1e9),
+        _ => (s.trim(), 1.0),
+    };
+    let frequency: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("{} is not a valid frequency", s))?;
+    Ok(frequency * multiplier)
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Render how much stronger each bin of AFTER is than the same bin of BEFORE, in dB. Sweeps are matched by the time since each input started, and only frequencies both cover are compared. Other options go before `diff`
+    Diff {
+        /// Capture to compare with, such as one before installing a filter
+        #[structopt(parse(from_os_str))]
+        before: PathBuf,
+
+        /// Capture to compare
+        #[structopt(parse(from_os_str))]
+        after: PathBuf,
+
+        /// Output file, or - to write to standard output
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(setting = structopt::clap::AppSettings::SubcommandsNegateReqs)]
 #[structopt(name = NAME, about = "Render .csv from rtl_power into images. Based on heatmap.py", version = VERSION, author = AUTHOR)]
 struct Opt {
     /// Verbose mode (-v, -vv, -vvv, etc)
@@ -53,13 +361,149 @@ struct Opt {
     #[structopt(short = "r", long = "recursive")]
     recursive: bool,
 
-    /// Input file
-    #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    /// Input files or glob patterns, or - to read from standard input
+    #[structopt(parse(from_os_str), required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Output file, or - to write to standard output. Defaults to the input file with a .png extension
+    #[structopt(short, long, parse(from_os_str), conflicts_with = "recursive")]
+    output: Option<PathBuf>,
+
+    /// Render all inputs into one image, ordered by their first timestamp. They have to cover the same frequencies
+    #[structopt(long, requires = "output", conflicts_with = "recursive")]
+    concat: bool,
+
+    /// Insert a line where each file starts when concatenating
+    #[structopt(long, requires = "concat")]
+    mark_boundaries: bool,
+
+    /// Stack sweeps in the order they were read, without empty rows for gaps in time
+    #[structopt(long)]
+    stack_rows: bool,
+
+    /// Combine bins so the waterfall is at most this many pixels wide
+    #[structopt(long)]
+    width: Option<usize>,
+
+    /// Combine sweeps so the waterfall, below the frequency header, is at most this many pixels high
+    #[structopt(long)]
+    height: Option<usize>,
+
+    /// How to combine values that end up in one pixel with --width or --height: mean (in linear power), max, min, median or a percentile such as p90
+    #[structopt(long, default_value = "mean")]
+    aggregation: OptAggregation,
+
+    /// Draw each bin this many pixels wide, for narrow captures
+    #[structopt(long, default_value = "1")]
+    scale_x: usize,
+
+    /// Draw each sweep this many pixels high. The frequency header is scaled to match
+    #[structopt(long, default_value = "1")]
+    scale_y: usize,
+
+    /// How to fill the pixels added by --scale-x and --scale-y: nearest (repeat each value) or linear
+    #[structopt(long, default_value = "nearest")]
+    interpolation: OptInterpolation,
+
+    /// Render each bin relative to its median over time, so intermittent signals stand out from the noise floor and fixed spurs
+    #[structopt(long)]
+    baseline: bool,
+
+    /// Render each bin relative to its median in this capture of the same frequencies, such as one with the antenna disconnected
+    #[structopt(long, parse(from_os_str), conflicts_with = "baseline")]
+    reference: Option<PathBuf>,
+
+    /// Render each sweep relative to its own level, so gain changes don't make whole rows brighter: median, a percentile such as p10, mean, min or max
+    #[structopt(long)]
+    normalize_sweeps: Option<OptAggregation>,
+
+    /// Skip or repair malformed lines (wrong column count, unparsable numbers, missing values) instead of failing
+    #[structopt(long)]
+    lenient: bool,
+
+    /// Tool that wrote the input: rtl_power, hackrf_sweep, soapy_power, soapy_power_bin or rtl_power_fftw. Detected from the first lines by default
+    #[structopt(long)]
+    input_format: Option<OptInputFormat>,
+
+    /// How to combine hops that overlap, when they have to be resampled onto one frequency grid: average (in linear power), max or center (the hop whose center is closest)
+    #[structopt(long, default_value = "average")]
+    overlap: OptOverlap,
+
+    /// Render only frequencies from this one up, in Hz or with a k, M or G suffix, such as 433.05M
+    #[structopt(long, parse(try_from_str = parse_frequency))]
+    freq_min: Option<f64>,
+
+    /// Render only frequencies up to this one, in Hz or with a k, M or G suffix, such as 434.79M
+    #[structopt(long, parse(try_from_str = parse_frequency))]
+    freq_max: Option<f64>,
+
+    /// Render only sweeps from this time on: a date and time in the input's time zone, such as '2021-10-31 01:30', one with an offset, such as 2021-10-31T01:30:00+01:00, or time since the first sweep, such as +1h30m
+    #[structopt(long)]
+    start: Option<OptTime>,
 
-    /// Choose a function that converts signal value to a color. (Default: RGB: [0-255,0-255,50], Extended: like default, with more steps)
-    #[structopt(short, long, default_value = "default")]
-    palette: OptPalette,
+    /// Render only sweeps up to this time, in the same forms as --start
+    #[structopt(long)]
+    end: Option<OptTime>,
+
+    /// What to do with sweeps that have a different number of bins than the others: pad (fill with NaN or cut off) or drop. Their timestamps are logged either way
+    #[structopt(long, default_value = "pad")]
+    width_mismatch: OptWidthPolicy,
+
+    /// Time zone the input's timestamps were written in, such as Europe/Prague. soapy_power_bin and rtl_power_fftw always write UTC
+    #[structopt(long, default_value = "UTC")]
+    timezone: Tz,
+
+    /// Time zone to give times in, such as in the SigMF metadata
+    #[structopt(long, default_value = "UTC")]
+    output_timezone: Tz,
+
+    /// Choose a function that converts signal value to a color. (Default: RGB: [0-255,0-255,50], Extended: like default, with more steps, Diverging: blue to white to red, for values around zero such as with --baseline or diff). Defaults to default, or to diverging with diff
+    #[structopt(short, long)]
+    palette: Option<OptPalette>,
+
+    /// How values are spread over the palette: linear, gamma[:POWER] (0.5 by default, lower gives weak signals more colors), sigmoid[:STEEPNESS] (10 by default) or equalize (each color used about as often)
+    #[structopt(long, default_value = "linear")]
+    transfer: OptTransfer,
+
+    /// Write SigMF metadata into a .sigmf-meta file next to the image
+    #[structopt(long)]
+    sigmf: bool,
+
+    /// Hardware notes for the SigMF metadata, such as the receiver and antenna
+    #[structopt(long, requires = "sigmf")]
+    hw: Option<String>,
+
+    /// Description for the SigMF metadata
+    #[structopt(long, requires = "sigmf")]
+    description: Option<String>,
+
+    /// Label a frequency range in the SigMF metadata, as LOW-HIGH=LABEL in Hz, such as 88e6-108e6=FM. Can be repeated
+    #[structopt(long = "annotate", requires = "sigmf", number_of_values = 1)]
+    annotations: Vec<OptAnnotation>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// Expands inputs that don't exist, but look like glob patterns, such as `captures/*.csv.gz`.
+/// Shells usually do this already, but not on Windows or when the pattern is quoted.
+fn expand(inputs: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        let pattern = input.to_string_lossy();
+        if input.exists() || !pattern.contains(['*', '?', '[']) {
+            expanded.push(input);
+            continue;
+        }
+        let matches = glob::glob(&pattern)
+            .with_context(|| format!("Invalid pattern '{}'", pattern))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            bail!("No files match '{}'", pattern);
+        }
+        expanded.extend(matches);
+    }
+    Ok(expanded)
 }
 
 fn main() -> Result<()> {
@@ -73,30 +517,201 @@ fn main() -> Result<()> {
 
     debug!("Options: {:?}", options);
 
-    let input = options.input;
-    let exts = vec![".csv", ".csv.gz"];
-    let palette = options.palette.into();
-
-    if options.recursive {
-        for entry in WalkDir::new(input) {
-            let entry = entry?;
-            let name = entry.file_name().to_string_lossy();
-            if exts.iter().any(|ext| name.ends_with(ext)) {
-                sdr_heatmap::main(entry.path(), palette)
-                    .context(format!("Error on file '{}'", entry.path().display()))?;
+    let inputs = expand(options.inputs)?;
+    let render_options = RenderOptions {
+        palette: match (options.palette, &options.command) {
+            (Some(palette), _) => palette.into(),
+            (None, Some(Command::Diff { .. })) => Palette::Diverging,
+            (None, None) => Palette::Default,
+        },
+        metadata: if options.sigmf {
+            Some(Metadata {
+                hw: options.hw,
+                description: options.description,
+                annotations: options.annotations.into_iter().map(|a| a.0).collect(),
+            })
+        } else {
+            None
+        },
+        mark_boundaries: options.mark_boundaries,
+        stack_rows: options.stack_rows,
+        timezone: options.output_timezone,
+        width: options.width,
+        height: options.height,
+        aggregation: options.aggregation.0,
+        scale: if (options.scale_x, options.scale_y) != (1, 1) {
+            Some(Scale {
+                x: options.scale_x,
+                y: options.scale_y,
+                interpolation: options.interpolation.into(),
+            })
+        } else {
+            None
+        },
+        baseline: match (options.reference, options.baseline) {
+            (Some(reference), _) => Some(Baseline::Reference(reference)),
+            (None, true) => Some(Baseline::Median),
+            (None, false) => None,
+        },
+        sweep_level: options.normalize_sweeps.map(|level| level.0),
+        transfer: options.transfer.0,
+    };
+    if options.width == Some(0) || options.height == Some(0) {
+        bail!("--width and --height have to be at least 1");
+    }
+    if options.scale_x == 0 || options.scale_y == 0 {
+        bail!("--scale-x and --scale-y have to be at least 1");
+    }
+    let timezone = options.timezone;
+    let read_options = ReadOptions {
+        lenient: options.lenient,
+        format: options.input_format.map(Into::into),
+        timezone: options.timezone,
+        overlap: options.overlap.into(),
+        mismatch: options.width_mismatch.into(),
+        freq_min: options.freq_min,
+        freq_max: options.freq_max,
+        start: options
+            .start
+            .map(|start| start.bound(timezone))
+            .transpose()?,
+        end: options.end.map(|end| end.bound(timezone)).transpose()?,
+    };
+    if let (Some(min), Some(max)) = (options.freq_min, options.freq_max) {
+        if min >= max {
+            bail!("--freq-min has to be lower than --freq-max");
+        }
+    }
+
+    let stdio = Path::new("-");
+    let output = options.output.map(|output| {
+        if output == stdio {
+            Output::Stdout
+        } else {
+            Output::File(output)
+        }
+    });
+    let to_stdout = match &output {
+        Some(output) => matches!(output, Output::Stdout),
+        None => !options.concat && inputs.iter().any(|input| input == stdio),
+    };
+    if render_options.metadata.is_some() && to_stdout {
+        bail!("SigMF metadata can only be written next to an output file");
+    }
+
+    if let Some(Command::Diff {
+        before,
+        after,
+        output: diff_output,
+    }) = options.command
+    {
+        let ignored = [
+            ("--recursive", options.recursive),
+            ("--concat", options.concat),
+            ("--output", output.is_some()),
+            ("--baseline", options.baseline),
+            (
+                "--reference",
+                render_options.baseline.is_some() && !options.baseline,
+            ),
+            ("--normalize-sweeps", render_options.sweep_level.is_some()),
+            ("--sigmf", render_options.metadata.is_some()),
+        ];
+        if let Some((option, _)) = ignored.iter().find(|(_, set)| *set) {
+            bail!("{} can't be used with diff", option);
+        }
+        if !inputs.is_empty() {
+            bail!("diff takes its inputs after it, as BEFORE AFTER");
+        }
+        if before == stdio || after == stdio {
+            bail!("Standard input can't be compared with another file");
+        }
+        let output = if diff_output == stdio {
+            Output::Stdout
+        } else {
+            Output::File(diff_output)
+        };
+        sdr_heatmap::render_diff(&before, &after, &output, &render_options, &read_options)
+            .context("Error comparing files")?;
+    } else if options.recursive {
+        for input in inputs {
+            for entry in WalkDir::new(input) {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy();
+                if sdr_heatmap::EXTENSIONS
+                    .iter()
+                    .any(|ext| name.ends_with(ext))
+                {
+                    sdr_heatmap::main(entry.path(), &render_options, &read_options)
+                        .context(format!("Error on file '{}'", entry.path().display()))?;
+                }
             }
         }
+    } else if options.concat {
+        if inputs.iter().any(|input| input == stdio) {
+            bail!("Standard input can't be concatenated with other files");
+        }
+        let output = output.context("Concatenating needs an output file")?;
+        sdr_heatmap::render_many(&inputs, &output, &render_options, &read_options)
+            .context("Error concatenating files")?;
     } else {
-        sdr_heatmap::main(&input, palette)
-            .context(format!("Error on file '{}'", input.display()))?;
+        if inputs.len() > 1 && output.is_some() {
+            bail!("Several inputs can only be written to one output with --concat");
+        }
+        for input in inputs {
+            let output = match &output {
+                Some(output) => output.clone(),
+                None if input == stdio => Output::Stdout,
+                None => Output::File(input.with_extension("png")),
+            };
+            let input = if input == stdio {
+                Input::Stdin
+            } else {
+                Input::File(input)
+            };
+            sdr_heatmap::render(&input, &output, &render_options, &read_options)
+                .context(format!("Error on {}", input))?;
+        }
     };
     Ok(())
 }
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn dummy() {
         assert_eq!(4, 2 + 2);
     }
+
+    #[test]
+    fn frequencies() {
+        assert_eq!(parse_frequency("433.05M").unwrap(), 433.05e6);
+        assert_eq!(parse_frequency("24e6").unwrap(), 24e6);
+        assert_eq!(parse_frequency("1.7G").unwrap(), 1.7e9);
+        assert_eq!(parse_frequency("100k").unwrap(), 100e3);
+        assert!(parse_frequency("fast").is_err());
+    }
+
+    #[test]
+    fn times() {
+        let offset = |s: &str| match s.parse() {
+            Ok(OptTime::Offset(offset)) => Some(offset.num_seconds()),
+            _ => None,
+        };
+        assert_eq!(offset("+1h30m"), Some(5400));
+        assert_eq!(offset("+1d2s"), Some(86402));
+        assert_eq!(offset("+1.5m"), Some(90));
+        assert_eq!(offset("+10"), None);
+        assert_eq!(offset("+"), None);
+
+        let london = chrono_tz::Europe::London;
+        let at = |s: &str| match s.parse::<OptTime>().unwrap().bound(london).unwrap() {
+            TimeBound::At(time) => time.to_rfc3339(),
+            bound => panic!("{:?}", bound),
+        };
+        assert_eq!(at("2021-10-30 12:00"), "2021-10-30T11:00:00+00:00");
+        assert_eq!(at("2021-10-30T12:00:00Z"), "2021-10-30T12:00:00+00:00");
+        assert!("yesterday".parse::<OptTime>().is_err());
+    }
 }
@@ -0,0 +1,36 @@
+use super::{rescale_value_to, PaletteColorize};
+
+/// Blue below the middle of the range, white in it and red above, for differences centered at zero
+pub struct DivergingPalette {}
+impl PaletteColorize for DivergingPalette {
+    fn get_color(&self, value: f32) -> [u8; 3] {
+        if value.is_nan() {
+            return [0, 0, 0];
+        }
+        if value < 0.5 {
+            let fade = rescale_value_to(value * 2.0, 0.0, 255.0) as u8;
+            [fade, fade, 255]
+        } else {
+            let fade = rescale_value_to((1.0 - value) * 2.0, 0.0, 255.0) as u8;
+            [255, fade, fade]
+        }
+    }
+    fn get_color_under_range(&self) -> [u8; 3] {
+        [0, 0, 128]
+    }
+    fn get_color_over_range(&self) -> [u8; 3] {
+        [128, 0, 0]
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn centered() {
+        let palette = DivergingPalette {};
+        assert_eq!(palette.get_color(0.0), [0, 0, 255]);
+        assert_eq!(palette.get_color(0.5), [255, 255, 255]);
+        assert_eq!(palette.get_color(1.0), [255, 0, 0]);
+    }
+}
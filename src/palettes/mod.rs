@@ -1,11 +1,15 @@
 use log::*;
 mod default;
+mod diverging;
 mod extended;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Default)]
 pub enum Palette {
+    #[default]
     Default,
     Extended,
+    /// Blue for negative values, white for zero and red for positive ones, if the range is centered at zero
+    Diverging,
 }
 
 impl Palette {
@@ -13,6 +17,7 @@ impl Palette {
         match self {
             Palette::Default => Box::from(default::DefaultPalette {}),
             Palette::Extended => Box::from(extended::ExtendedPalette {}),
+            Palette::Diverging => Box::from(diverging::DivergingPalette {}),
         }
     }
 }
@@ -34,19 +39,123 @@ fn rescale_value_to(value: f32, min: f32, max: f32) -> f32 {
     value.mul_add(max, min)
 }
 
+/// Number of steps of the histogram for [`Transfer::Equalize`]
+const HISTOGRAM_BINS: usize = 1024;
+
+/// How values between min and max are spread over the palette
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Transfer {
+    /// Evenly, so each dB gets as much of the palette
+    #[default]
+    Linear,
+    /// Raised to this power, so below 1 weak signals near the noise floor get more of the palette
+    Gamma(f32),
+    /// Along an S-shaped curve of this steepness, which adds contrast around the middle of the range
+    Sigmoid(f32),
+    /// So that each color is used about as often, from a histogram of the values.
+    /// The histogram is built when the image is colored rather than while the capture is read,
+    /// so it covers the values that are drawn: normalized, downsampled and without dropped sweeps.
+    Equalize,
+}
+
+/// Maps values from min to max onto the palette, through a [`Transfer`] function
+#[derive(Clone, Debug)]
+pub struct ColorScale {
+    pub min: f32,
+    pub max: f32,
+    transfer: Transfer,
+    /// Share of values up to each step of the histogram, for [`Transfer::Equalize`]
+    cdf: Vec<f32>,
+}
+
+impl ColorScale {
+    pub fn linear(min: f32, max: f32) -> Self {
+        Self::new(Transfer::Linear, min, max, &[])
+    }
+
+    /// Creates a scale, with the histogram of `values` if `transfer` needs it
+    pub fn new(transfer: Transfer, min: f32, max: f32, values: &[f32]) -> Self {
+        let cdf = if transfer == Transfer::Equalize {
+            equalize(values, min, max)
+        } else {
+            Vec::new()
+        };
+        Self {
+            min,
+            max,
+            transfer,
+            cdf,
+        }
+    }
+
+    /// Places value on a scale from 0 to 1, or outside of it if it isn't between min and max
+    fn rescale(&self, value: f32) -> f32 {
+        let scaled = rescale_value_from(value, self.min, self.max);
+        if !(0.0..=1.0).contains(&scaled) {
+            return scaled;
+        }
+        match self.transfer {
+            Transfer::Linear => scaled,
+            Transfer::Gamma(gamma) => scaled.powf(gamma),
+            Transfer::Sigmoid(steepness) => {
+                let sigmoid = |x: f32| 1.0 / (1.0 + (-steepness * (x - 0.5)).exp());
+                let (low, high) = (sigmoid(0.0), sigmoid(1.0));
+                (sigmoid(scaled) - low) / (high - low)
+            }
+            Transfer::Equalize if self.cdf.is_empty() => scaled,
+            Transfer::Equalize => {
+                self.cdf[((scaled * HISTOGRAM_BINS as f32) as usize).min(HISTOGRAM_BINS - 1)]
+            }
+        }
+    }
+
+    /// Returns the color of value using the specified palette
+    pub fn color(&self, palette: Palette, value: f32) -> [u8; 3] {
+        let (min, max) = (self.min, self.max);
+        let scaled = self.rescale(value);
+        let palette = palette.instance();
+        if scaled < 0.0 {
+            warn!("Computed invalid color! Value range: {} to {}, Value: {}, Color range: 0-255, Color: {}", min,max,value,scaled);
+            palette.get_color_under_range()
+        } else if scaled > 1.0 {
+            warn!("Computed invalid color! Value range: {} to {}, Value: {}, Color range: 0-255, Color: {}", min,max,value,scaled);
+            palette.get_color_over_range()
+        } else {
+            palette.get_color(scaled)
+        }
+    }
+}
+
+/// Cumulative histogram of the values between min and max, scaled so the lowest step is 0 and the highest 1.
+/// Empty if there are no such values or they are all the same.
+fn equalize(values: &[f32], min: f32, max: f32) -> Vec<f32> {
+    let mut histogram = vec![0u64; HISTOGRAM_BINS];
+    for &value in values {
+        let scaled = rescale_value_from(value, min, max);
+        if (0.0..=1.0).contains(&scaled) {
+            histogram[((scaled * HISTOGRAM_BINS as f32) as usize).min(HISTOGRAM_BINS - 1)] += 1;
+        }
+    }
+    let cdf = histogram
+        .iter()
+        .scan(0, |sum, count| {
+            *sum += count;
+            Some(*sum)
+        })
+        .collect::<Vec<_>>();
+    let lowest = cdf.iter().copied().find(|&sum| sum > 0).unwrap_or(0);
+    let total = cdf[HISTOGRAM_BINS - 1];
+    if total == lowest {
+        return Vec::new();
+    }
+    cdf.into_iter()
+        .map(|sum| (sum.saturating_sub(lowest) as f64 / (total - lowest) as f64) as f32)
+        .collect()
+}
+
 /// Places value on a scale from min to max, and transforms it to an integer scale from 0 to 255. Returns a color using the specified palette.
 pub fn scale_tocolor(palette: Palette, value: f32, min: f32, max: f32) -> [u8; 3] {
-    let scaled = rescale_value_from(value, min, max);
-    let palette = palette.instance();
-    if scaled < 0.0 {
-        warn!("Computed invalid color! Value range: {} to {}, Value: {}, Color range: 0-255, Color: {}", min,max,value,scaled);
-        palette.get_color_under_range()
-    } else if scaled > 1.0 {
-        warn!("Computed invalid color! Value range: {} to {}, Value: {}, Color range: 0-255, Color: {}", min,max,value,scaled);
-        palette.get_color_over_range()
-    } else {
-        palette.get_color(scaled)
-    }
+    ColorScale::linear(min, max).color(palette, value)
 }
 
 #[cfg(test)]
@@ -99,6 +208,23 @@ mod tests {
             [0, 0, 0]
         );
     }
+    #[test]
+    fn transfer_functions() {
+        let red = |transfer, value| {
+            let values = [0.0, 1.0, 2.0, 3.0, 4.0, 100.0];
+            ColorScale::new(transfer, 0.0, 100.0, &values).color(Palette::Default, value)[0]
+        };
+        assert_eq!(red(Transfer::Linear, 25.0), 63);
+        assert_eq!(red(Transfer::Gamma(0.5), 25.0), 127);
+        assert_eq!(red(Transfer::Sigmoid(10.0), 50.0), 127);
+        assert!(red(Transfer::Sigmoid(10.0), 25.0) < 63);
+        assert_eq!(red(Transfer::Sigmoid(10.0), 100.0), 255);
+        // Most values are near the bottom, so they get most of the colors
+        assert_eq!(red(Transfer::Equalize, 0.0), 0);
+        assert_eq!(red(Transfer::Equalize, 4.0), 204);
+        assert_eq!(red(Transfer::Equalize, 100.0), 255);
+    }
+
     proptest! {
         #[test]
         fn scale_tocolor_within_bounds(
@@ -0,0 +1,449 @@
+//! Zero-copy parser for the rtl_power line format: six header columns followed by signal values.
+
+use crate::error::{Error, Result};
+use memchr::memchr;
+use std::{cmp::Ordering, fmt, io::BufRead};
+
+/// Number of columns before the signal values start: date, time, freq_low, freq_high, freq_step, samples
+const HEADER_COLUMNS: usize = 6;
+
+/// A single line of an rtl_power file, borrowing from the buffer it was parsed from.
+/// Signal values are parsed lazily by [`Line::values`].
+#[derive(Debug, Clone)]
+pub struct Line<'a> {
+    pub date: &'a str,
+    pub time: &'a str,
+    pub freq_low: u64,
+    pub freq_high: u64,
+    pub freq_step: f64,
+    pub samples: u32,
+    values: Fields<'a>,
+}
+
+impl<'a> Line<'a> {
+    pub fn parse(line: &'a [u8]) -> Result<Self> {
+        let mut fields = Fields::new(line);
+        let mut header = [&[][..]; HEADER_COLUMNS];
+        for (i, column) in header.iter_mut().enumerate() {
+            *column = fields.next().ok_or_else(|| {
+                Error::csv(
+                    None,
+                    format!("Expected at least {} columns, found {}", HEADER_COLUMNS, i),
+                )
+            })?;
+        }
+        Ok(Line {
+            date: parse_str(header[0], 1)?,
+            time: parse_str(header[1], 2)?,
+            freq_low: parse_frequency(header[2], 3)?,
+            freq_high: parse_frequency(header[3], 4)?,
+            freq_step: parse_number(header[4], 5)?,
+            samples: parse_number(header[5], 6)?,
+            values: fields,
+        })
+    }
+
+    /// Number of bins between `freq_low` and `freq_high`, if the frequencies make sense
+    pub fn bins(&self) -> Option<usize> {
+        if self.freq_high < self.freq_low
+            || self.freq_step.partial_cmp(&0.0) != Some(Ordering::Greater)
+        {
+            return None;
+        }
+        let bins = ((self.freq_high - self.freq_low) as f64 / self.freq_step).round();
+        if bins.is_finite() {
+            Some(bins as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Signal values of this line, including the extra trailing bin rtl_power writes
+    pub fn values(&self) -> impl Iterator<Item = Result<f32>> + 'a {
+        self.values
+            .clone()
+            .zip(HEADER_COLUMNS + 1..)
+            .map(|(field, column)| parse_f32(field, column))
+    }
+}
+
+/// Comma separated fields with surrounding whitespace trimmed
+#[derive(Debug, Clone)]
+struct Fields<'a> {
+    rest: Option<&'a [u8]>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a [u8]) -> Self {
+        Self { rest: Some(line) }
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest?;
+        match memchr(b',', rest) {
+            Some(i) => {
+                self.rest = Some(&rest[i + 1..]);
+                Some(trim(&rest[..i]))
+            }
+            None => {
+                self.rest = None;
+                Some(trim(rest))
+            }
+        }
+    }
+}
+
+/// Number of comma separated columns in a line
+pub fn column_count(line: &[u8]) -> usize {
+    memchr::memchr_iter(b',', line).count() + 1
+}
+
+pub(crate) fn trim(field: &[u8]) -> &[u8] {
+    let start = field
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(field.len());
+    let end = field
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |i| i + 1);
+    &field[start..end]
+}
+
+fn parse_str(field: &[u8], column: usize) -> Result<&str> {
+    std::str::from_utf8(field).map_err(|_| Error::csv(Some(column), "Column is not valid UTF-8"))
+}
+
+fn parse_number<T: std::str::FromStr>(field: &[u8], column: usize) -> Result<T> {
+    parse_str(field, column)?
+        .parse()
+        .map_err(|_| Error::number(column, String::from_utf8_lossy(field)))
+}
+
+/// Parses a frequency in Hz, which soapy_power writes as a float, such as `88000000.0`
+pub(crate) fn parse_frequency(field: &[u8], column: usize) -> Result<u64> {
+    parse_number(field, column).or_else(|err| match parse_number::<f64>(field, column) {
+        Ok(freq) if freq >= 0.0 && freq <= u64::MAX as f64 => Ok(freq.round() as u64),
+        _ => Err(err),
+    })
+}
+
+pub(crate) fn parse_f32(field: &[u8], column: usize) -> Result<f32> {
+    if field == b"-nan" || field == b"nan" {
+        Ok(f32::NAN)
+    } else {
+        fast_float::parse(field).map_err(|_| Error::number(column, String::from_utf8_lossy(field)))
+    }
+}
+
+/// Location of a line in the input, used to point at broken records
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    /// Line number, starting at 1
+    pub line: u64,
+    /// Offset of the start of the line from the start of the input
+    pub byte: u64,
+}
+
+impl Position {
+    /// Position of `line`, which has to be a subslice of `data`
+    pub fn of(data: &[u8], line: &[u8]) -> Self {
+        let byte = line.as_ptr() as usize - data.as_ptr() as usize;
+        Self {
+            line: memchr::memchr_iter(b'\n', &data[..byte]).count() as u64 + 1,
+            byte: byte as u64,
+        }
+    }
+}
+
+impl From<&csv::Position> for Position {
+    fn from(position: &csv::Position) -> Self {
+        Self {
+            line: position.line(),
+            byte: position.byte(),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, byte {}", self.line, self.byte)
+    }
+}
+
+/// Non-empty lines of an in-memory buffer, such as a memory-mapped file
+pub fn lines(data: &[u8]) -> impl DoubleEndedIterator<Item = &[u8]> {
+    data.split(|&b| b == b'\n')
+        .map(trim)
+        .filter(|line| !line.is_empty())
+}
+
+/// Splits `data` into at most `parts` chunks of roughly equal size, each ending at a line boundary,
+/// so they can be parsed independently
+pub fn split_lines(data: &[u8], parts: usize) -> Vec<&[u8]> {
+    let target = data.len() / parts.max(1) + 1;
+    let mut chunks = Vec::with_capacity(parts);
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = if rest.len() <= target {
+            rest.len()
+        } else {
+            memchr(b'\n', &rest[target..]).map_or(rest.len(), |i| target + i + 1)
+        };
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Date and time columns of a line, compared raw to tell whether two lines belong to the same sweep
+fn timestamp(line: &[u8]) -> &[u8] {
+    let mut commas = memchr::memchr_iter(b',', line);
+    match (commas.next(), commas.next()) {
+        (Some(_), Some(end)) => &line[..end],
+        _ => line,
+    }
+}
+
+/// Same as [`split_lines`], but moves lines to the previous chunk while they share its last timestamp,
+/// so no sweep is split between chunks
+pub fn split_sweeps(data: &[u8], parts: usize) -> Vec<&[u8]> {
+    let mut chunks = Vec::with_capacity(parts);
+    let mut start = 0;
+    let mut chunk_end = 0;
+    for chunk in split_lines(data, parts) {
+        chunk_end += chunk.len();
+        if chunk_end <= start {
+            continue;
+        }
+        let mut end = chunk_end;
+        if let Some(last) = lines(&data[start..end]).next_back() {
+            let key = timestamp(last);
+            while end < data.len() {
+                let next = memchr(b'\n', &data[end..]).map_or(data.len(), |i| end + i + 1);
+                let line = trim(&data[end..next]);
+                if !line.is_empty() && timestamp(line) != key {
+                    break;
+                }
+                end = next;
+            }
+        }
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+/// Calls `f` with every non-empty line of `reader`, reusing a single buffer for all of them.
+/// Errors are annotated with the position of the line that caused them.
+pub fn for_each_line<R, F>(reader: R, f: F) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    read_lines(reader, true, f)
+}
+
+/// Same as [`for_each_line`], but also calls `f` with empty lines, for formats where they separate blocks
+pub fn for_each_line_with_blanks<R, F>(reader: R, f: F) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    read_lines(reader, false, f)
+}
+
+fn read_lines<R, F>(mut reader: R, skip_empty: bool, mut f: F) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    let mut buf = Vec::new();
+    let mut position = Position::default();
+    loop {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        position.line += 1;
+        let start = position;
+        position.byte += read as u64;
+        let line = trim(&buf);
+        if skip_empty && line.is_empty() {
+            continue;
+        }
+        f(line).map_err(|e| e.at(start))?;
+    }
+}
+
+/// Calls `f` with every non-empty line of `chunk`, which is a part of `data`.
+/// Errors are annotated with the position of the line in `data`.
+pub fn for_each_line_in<'a, F>(data: &'a [u8], chunk: &'a [u8], mut f: F) -> Result<()>
+where
+    F: FnMut(&'a [u8]) -> Result<()>,
+{
+    for line in lines(chunk) {
+        f(line).map_err(|e| e.at(Position::of(data, line)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &[u8] =
+        b"2019-08-17, 22:37:25, 24000000, 25800000, 3515.62, 2, -13.69, nan, 4.5, -nan\r\n";
+
+    #[test]
+    fn parse_header() {
+        let line = Line::parse(trim(LINE)).unwrap();
+        assert_eq!(line.date, "2019-08-17");
+        assert_eq!(line.time, "22:37:25");
+        assert_eq!(line.freq_low, 24000000);
+        assert_eq!(line.freq_high, 25800000);
+        assert_eq!(line.freq_step, 3515.62);
+        assert_eq!(line.samples, 2);
+    }
+
+    #[test]
+    fn parse_values() {
+        let line = Line::parse(trim(LINE)).unwrap();
+        let values = line.values().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(values.len(), 4);
+        assert_eq!(values[0], -13.69);
+        assert!(values[1].is_nan());
+        assert_eq!(values[2], 4.5);
+        assert!(values[3].is_nan());
+    }
+
+    #[test]
+    fn matches_csv() {
+        let mut record = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(LINE)
+            .into_records()
+            .next()
+            .unwrap()
+            .unwrap();
+        record.trim();
+        let line = Line::parse(trim(LINE)).unwrap();
+        let fields = Fields::new(trim(LINE))
+            .map(|f| std::str::from_utf8(f).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(record.iter().collect::<Vec<_>>(), fields);
+        assert_eq!(record.len() - 6, line.values().count());
+    }
+
+    #[test]
+    fn bins() {
+        let line = Line::parse(trim(LINE)).unwrap();
+        assert_eq!(line.bins(), Some(512));
+        let line = Line::parse(b"2019-08-17, 22:37:25, 1, 2, 0, 4, 5.0").unwrap();
+        assert_eq!(line.bins(), None);
+    }
+
+    #[test]
+    fn float_frequencies() {
+        let line = Line::parse(b"2019-08-17, 22:37:25.5, 88000000.0, 88100000.0, 50000.0, 4, 5.0")
+            .unwrap();
+        assert_eq!(line.freq_low, 88000000);
+        assert_eq!(line.bins(), Some(2));
+        assert!(Line::parse(b"2019-08-17, 22:37:25.5, -1.0, 88100000.0, 1, 4, 5.0").is_err());
+    }
+
+    #[test]
+    fn too_few_columns() {
+        assert!(Line::parse(b"2019-08-17, 22:37:25, 24000000").is_err());
+    }
+
+    #[test]
+    fn invalid_float() {
+        let line = Line::parse(b"2019-08-17, 22:37:25, 1, 2, 3, 4, 5.0, abc").unwrap();
+        match line.values().collect::<Result<Vec<_>>>() {
+            Err(Error::Number { column, value, .. }) => {
+                assert_eq!(column, 8);
+                assert_eq!(value, "abc");
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(matches!(
+            Line::parse(b"2019-08-17, 22:37:25, 1, x, 3, 4, 5.0"),
+            Err(Error::Number { column: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn skips_empty_lines() {
+        let mut count = 0;
+        for_each_line(
+            &b"\n2019-08-17, 22:37:25, 1, 2, 3, 4, 5.0\n\r\n"[..],
+            |_| {
+                count += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn error_position() {
+        let data = "2019-08-17, 22:37:25, 1, 2, 3, 4, 5.0\n\n2019-08-17, 22:37:25, 1, 2, 3, 4, x\n";
+        let expected = Position { line: 3, byte: 39 };
+        let parse = |line: &[u8]| Line::parse(line)?.values().try_for_each(|v| v.map(drop));
+        let err = for_each_line(data.as_bytes(), parse).unwrap_err();
+        assert_eq!(err.position(), Some(expected));
+        let err = for_each_line_in(data.as_bytes(), data.as_bytes(), parse).unwrap_err();
+        assert_eq!(err.position(), Some(expected));
+        assert!(matches!(err, Error::Number { column: 7, .. }));
+    }
+
+    #[test]
+    fn lines_in_slice() {
+        let data = b"a\n\nb\r\n  \nc";
+        assert_eq!(lines(data).collect::<Vec<_>>(), vec![b"a", b"b", b"c"]);
+        assert_eq!(lines(data).next_back(), Some(&b"c"[..]));
+    }
+
+    #[test]
+    fn split_at_line_boundaries() {
+        let data = b"line one\nline two\nline three\nline four\n";
+        for parts in 1..10 {
+            let chunks = split_lines(data, parts);
+            assert!(chunks.len() <= parts);
+            assert_eq!(chunks.concat(), data.to_vec());
+            for chunk in chunks {
+                assert!(chunk.ends_with(b"\n"));
+            }
+        }
+        assert!(split_lines(b"", 4).is_empty());
+    }
+
+    #[test]
+    fn split_at_sweep_boundaries() {
+        let data = b"d, 1, a\nd, 1, b\nd, 1, c\nd, 2, a\n\nd, 2, b\nd, 3, a\n";
+        for parts in 1..10 {
+            let chunks = split_sweeps(data, parts);
+            assert!(chunks.len() <= parts);
+            assert_eq!(chunks.concat(), data.to_vec());
+            for chunk in chunks {
+                let first = lines(chunk).next().unwrap();
+                let before = &data[..chunk.as_ptr() as usize - data.as_ptr() as usize];
+                if let Some(previous) = lines(before).next_back() {
+                    assert_ne!(timestamp(previous), timestamp(first));
+                }
+            }
+        }
+    }
+}
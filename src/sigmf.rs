@@ -0,0 +1,310 @@
+//! SigMF-style metadata describing a rendered capture, written next to the image as `.sigmf-meta`.
+//! Every sweep that was read counts as a sample, whatever rows the image has. The image isn't a SigMF dataset,
+//! so the recording is metadata only, and links the image with the `heatmap` extension.
+
+use crate::error::{Error, Result};
+use crate::formats;
+use crate::lenient::{ReadOptions, Report};
+use crate::sweep::{Hop, Sweep};
+use crate::timezone::{format_in, Clock, Tz};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+const SIGMF_VERSION: &str = "1.0.0";
+
+/// Namespace of the fields about the rendered image
+const EXTENSION: &str = "heatmap";
+const EXTENSION_VERSION: &str = "1.0.0";
+
+/// Notes about a capture that can't be read from the file, such as the hardware it was recorded with
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub hw: Option<String>,
+    pub description: Option<String>,
+    pub annotations: Vec<Annotation>,
+}
+
+/// A labeled frequency range, covering the whole capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub freq_low: f64,
+    pub freq_high: f64,
+    pub label: String,
+}
+
+/// Frequency range and time span of a capture
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extent {
+    pub freq_low: Option<u64>,
+    pub freq_high: Option<u64>,
+    /// When the first sweep was measured
+    pub first: Option<DateTime<Utc>>,
+    /// When the last sweep was measured
+    pub last: Option<DateTime<Utc>>,
+    pub sweeps: usize,
+    /// Hops of the first sweep, which the bins of the image line up with
+    pub grid: Vec<Hop>,
+}
+
+impl Extent {
+    /// Adds a sweep, with its timestamp read by `clock`
+    pub fn update(&mut self, sweep: &Sweep, clock: &mut Clock) {
+        self.add(
+            sweep,
+            sweep.timestamp().and_then(|time| clock.instant(time)),
+        );
+    }
+
+    /// Adds a sweep measured at `instant`
+    pub(crate) fn add(&mut self, sweep: &Sweep, instant: Option<DateTime<Utc>>) {
+        for hop in sweep.hops.iter() {
+            self.freq_low = Some(self.freq_low.map_or(hop.freq_low, |f| f.min(hop.freq_low)));
+            self.freq_high = Some(
+                self.freq_high
+                    .map_or(hop.freq_high, |f| f.max(hop.freq_high)),
+            );
+        }
+        if let Some(instant) = instant {
+            self.first = self.first.or(Some(instant));
+            self.last = Some(instant);
+        }
+        if self.grid.is_empty() {
+            self.grid = sweep.hops.clone();
+        }
+        self.sweeps += 1;
+    }
+
+    /// Combines extents of two captures, `b` following `a`
+    pub fn merge(a: Self, b: Self) -> Self {
+        Self {
+            freq_low: a.freq_low.into_iter().chain(b.freq_low).min(),
+            freq_high: a.freq_high.into_iter().chain(b.freq_high).max(),
+            first: a.first.or(b.first),
+            last: b.last.or(a.last),
+            sweeps: a.sweeps + b.sweeps,
+            grid: if a.grid.is_empty() { b.grid } else { a.grid },
+        }
+    }
+
+    /// Reads the extent of a capture the same way the image is rendered
+    pub fn read<R: Read>(file: R, options: &ReadOptions) -> Result<Self> {
+        let mut extent = Self::default();
+        let mut report = Report::default();
+        let (options, reader) = formats::read_start(file, options)?;
+        let options = &options;
+        let mut clock = Clock::new(options.timezone());
+        formats::for_each_sweep(reader, options, &mut report, |sweep| {
+            extent.update(&sweep, &mut clock);
+            Ok(())
+        })?;
+        Ok(extent)
+    }
+}
+
+#[derive(Serialize)]
+struct Meta<'a> {
+    global: Global<'a>,
+    captures: Vec<Capture>,
+    annotations: Vec<SigmfAnnotation<'a>>,
+}
+
+#[derive(Serialize)]
+struct Global<'a> {
+    /// Type of the values that were rendered, power in dB. SigMF requires it even without a dataset.
+    #[serde(rename = "core:datatype")]
+    datatype: &'a str,
+    #[serde(rename = "core:version")]
+    version: &'a str,
+    #[serde(rename = "core:recorder")]
+    recorder: String,
+    #[serde(rename = "core:metadata_only")]
+    metadata_only: bool,
+    #[serde(rename = "core:extensions")]
+    extensions: Vec<Extension<'a>>,
+    /// File name of the rendered image
+    #[serde(rename = "heatmap:image")]
+    image: &'a str,
+    #[serde(rename = "core:hw", skip_serializing_if = "Option::is_none")]
+    hw: Option<&'a str>,
+    #[serde(rename = "core:description", skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Extension<'a> {
+    name: &'a str,
+    version: &'a str,
+    optional: bool,
+}
+
+#[derive(Serialize)]
+struct Capture {
+    #[serde(rename = "core:sample_start")]
+    sample_start: usize,
+    #[serde(rename = "core:frequency", skip_serializing_if = "Option::is_none")]
+    frequency: Option<f64>,
+    /// Always UTC, as SigMF requires
+    #[serde(rename = "core:datetime", skip_serializing_if = "Option::is_none")]
+    datetime: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SigmfAnnotation<'a> {
+    #[serde(rename = "core:sample_start")]
+    sample_start: usize,
+    #[serde(rename = "core:sample_count")]
+    sample_count: usize,
+    #[serde(rename = "core:freq_lower_edge")]
+    freq_lower_edge: f64,
+    #[serde(rename = "core:freq_upper_edge")]
+    freq_upper_edge: f64,
+    #[serde(rename = "core:label")]
+    label: &'a str,
+    #[serde(rename = "core:comment", skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+/// Writes the metadata of `image`, the file name of the rendered image, into `writer`.
+/// Comments give times in `zone`.
+pub fn write_metadata<W: Write>(
+    writer: W,
+    image: &str,
+    extent: &Extent,
+    metadata: &Metadata,
+    zone: Tz,
+) -> Result<()> {
+    let mut annotations = Vec::new();
+    if let (Some(low), Some(high)) = (extent.freq_low, extent.freq_high) {
+        annotations.push(SigmfAnnotation {
+            sample_start: 0,
+            sample_count: extent.sweeps,
+            freq_lower_edge: low as f64,
+            freq_upper_edge: high as f64,
+            label: "capture",
+            comment: match (&extent.first, &extent.last) {
+                (Some(first), Some(last)) => Some(format!(
+                    "Sweeps from {} to {}",
+                    format_in(*first, zone),
+                    format_in(*last, zone)
+                )),
+                _ => None,
+            },
+        });
+    }
+    annotations.extend(metadata.annotations.iter().map(|a| SigmfAnnotation {
+        sample_start: 0,
+        sample_count: extent.sweeps,
+        freq_lower_edge: a.freq_low,
+        freq_upper_edge: a.freq_high,
+        label: &a.label,
+        comment: None,
+    }));
+    let meta = Meta {
+        global: Global {
+            datatype: "rf32_le",
+            version: SIGMF_VERSION,
+            recorder: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            metadata_only: true,
+            extensions: vec![Extension {
+                name: EXTENSION,
+                version: EXTENSION_VERSION,
+                optional: true,
+            }],
+            image,
+            hw: metadata.hw.as_deref(),
+            description: metadata.description.as_deref(),
+        },
+        captures: vec![Capture {
+            sample_start: 0,
+            frequency: match (extent.freq_low, extent.freq_high) {
+                (Some(low), Some(high)) => Some((low as f64 + high as f64) / 2.0),
+                _ => None,
+            },
+            datetime: extent.first.map(|first| format_in(first, Tz::UTC)),
+        }],
+        annotations,
+    };
+    serde_json::to_writer_pretty(writer, &meta).map_err(Error::Metadata)
+}
+
+/// Writes the metadata of the image at `image` into a `.sigmf-meta` file next to it
+pub fn save_metadata(image: &Path, extent: &Extent, metadata: &Metadata, zone: Tz) -> Result<()> {
+    let path = image.with_extension("sigmf-meta");
+    let name = image
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let file = File::create(&path).map_err(|source| Error::Create {
+        path: path.clone(),
+        source,
+    })?;
+    let mut writer = BufWriter::new(file);
+    write_metadata(&mut writer, &name, extent, metadata, zone)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata() {
+        let data = b"2019-08-17, 22:37:25, 100, 200, 50, 1, 1, 2, 3
+2019-08-17, 22:37:25, 200, 300, 50, 1, 1, 2, 3
+2019-08-17, 22:37:35, 100, 200, 50, 1, 1, 2, 3
+2019-08-17, 22:37:35, 200, 300, 50, 1, 1, 2, 3
+";
+        let options = ReadOptions {
+            timezone: chrono_tz::Europe::Prague,
+            ..ReadOptions::default()
+        };
+        let extent = Extent::read(&data[..], &options).unwrap();
+        assert_eq!(extent.freq_low, Some(100));
+        assert_eq!(extent.freq_high, Some(300));
+        let first = extent.first.map(|first| format_in(first, Tz::UTC));
+        assert_eq!(first.as_deref(), Some("2019-08-17T20:37:25Z"));
+        assert_eq!(extent.sweeps, 2);
+
+        let metadata = Metadata {
+            hw: Some("RTL-SDR v3, discone".to_string()),
+            description: None,
+            annotations: vec![Annotation {
+                freq_low: 150.0,
+                freq_high: 250.0,
+                label: "beacon".to_string(),
+            }],
+        };
+        let mut json = Vec::new();
+        write_metadata(
+            &mut json,
+            "capture.png",
+            &extent,
+            &metadata,
+            chrono_tz::Europe::Prague,
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["global"]["heatmap:image"], "capture.png");
+        assert_eq!(json["global"]["core:metadata_only"], true);
+        assert_eq!(json["global"]["core:extensions"][0]["name"], "heatmap");
+        assert!(json["global"].get("core:dataset").is_none());
+        assert_eq!(json["global"]["core:datatype"], "rf32_le");
+        assert_eq!(json["global"]["core:hw"], "RTL-SDR v3, discone");
+        assert!(json["global"].get("core:description").is_none());
+        assert_eq!(json["captures"][0]["core:frequency"], 200.0);
+        assert_eq!(json["captures"][0]["core:datetime"], "2019-08-17T20:37:25Z");
+        assert_eq!(
+            json["annotations"][0]["core:comment"],
+            "Sweeps from 2019-08-17T22:37:25+02:00 to 2019-08-17T22:37:35+02:00"
+        );
+        assert_eq!(json["annotations"][0]["core:sample_count"], 2);
+        assert_eq!(json["annotations"][1]["core:label"], "beacon");
+        assert_eq!(json["annotations"][1]["core:freq_lower_edge"], 150.0);
+    }
+}
@@ -0,0 +1,538 @@
+//! A sweep is one row of the image: all hops measured at the same time, ordered by frequency.
+
+use crate::error::{Error, Result};
+use crate::parser::Line;
+use chrono::NaiveDateTime;
+
+/// How tools write the date and time of a sweep, with optional fractions of a second
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// Resampling gives up if the grid would have more bins than this many times the values of the sweep,
+/// which is usually a broken step
+const MAX_RESAMPLE_FACTOR: usize = 64;
+
+/// A range of equally spaced bins, usually one line of the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hop {
+    pub freq_low: u64,
+    pub freq_high: u64,
+    pub freq_step: f64,
+    /// Index of the first value of this hop in [`Sweep::values`]
+    pub start: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sweep {
+    pub date: String,
+    pub time: String,
+    pub hops: Vec<Hop>,
+    /// Values of all hops, one after another
+    pub values: Vec<f32>,
+}
+
+impl Sweep {
+    pub fn width(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Date and time the sweep was measured at, `None` if they can't be parsed
+    pub fn timestamp(&self) -> Option<NaiveDateTime> {
+        let timestamp = format!("{} {}", self.date.trim(), self.time.trim());
+        NaiveDateTime::parse_from_str(&timestamp, TIMESTAMP_FORMAT).ok()
+    }
+
+    /// Values of the hop at `index`
+    pub fn hop_values(&self, index: usize) -> &[f32] {
+        let start = self.hops[index].start;
+        let end = self
+            .hops
+            .get(index + 1)
+            .map_or(self.values.len(), |hop| hop.start);
+        &self.values[start..end]
+    }
+
+    /// Whether hops follow each other without gaps or overlap and with the same step,
+    /// so the values are linear in frequency
+    pub fn is_linear(&self) -> bool {
+        let step = match self.hops.first() {
+            Some(hop) => hop.freq_step,
+            None => return true,
+        };
+        (0..self.hops.len()).all(|i| {
+            let hop = &self.hops[i];
+            let end = hop.freq_low as f64 + self.hop_values(i).len() as f64 * hop.freq_step;
+            (hop.freq_step - step).abs() <= step.abs() * 1e-9
+                && self
+                    .hops
+                    .get(i + 1)
+                    .is_none_or(|next| (next.freq_low as f64 - end).abs() < step / 2.0)
+        })
+    }
+
+    /// This sweep if its values are linear in frequency, otherwise [`Sweep::resample`]d
+    pub fn linearize(self, overlap: Overlap) -> Result<Self> {
+        if self.is_linear() {
+            Ok(self)
+        } else {
+            self.resample(overlap)
+        }
+    }
+
+    /// Resamples all hops onto one grid with the finest step of them, from the lowest to the highest frequency.
+    /// Every bin takes the value of the hop bins its center falls into, combined by `overlap`,
+    /// or NaN if it's in a gap between hops.
+    /// Fails if the grid would have more than `MAX_RESAMPLE_FACTOR` times as many bins as the hops have values,
+    /// such as when one hop has a tiny step.
+    pub fn resample(&self, overlap: Overlap) -> Result<Self> {
+        let step = self
+            .hops
+            .iter()
+            .map(|hop| hop.freq_step)
+            .filter(|step| *step > 0.0)
+            .fold(f64::INFINITY, f64::min);
+        let (low, high) = (0..self.hops.len()).fold((f64::INFINITY, 0.0f64), |(low, high), i| {
+            let hop = &self.hops[i];
+            let end = hop.freq_low as f64 + self.hop_values(i).len() as f64 * hop.freq_step;
+            (low.min(hop.freq_low as f64), high.max(end))
+        });
+        if !step.is_finite() || !low.is_finite() {
+            return Ok(self.clone());
+        }
+        let bins = ((high - low) / step).round();
+        let limit = self.values.len().max(1) * MAX_RESAMPLE_FACTOR;
+        if bins > limit as f64 {
+            return Err(Error::geometry(format!(
+                "Resampling the {} values of the sweep at {} {} onto a grid of {} Hz steps would take {} bins",
+                self.values.len(),
+                self.date,
+                self.time,
+                step,
+                bins
+            )));
+        }
+        let bins = bins as usize;
+        let mut values = vec![f32::NAN; bins];
+        // Number of values for an average, distance from the center of the hop to prefer one
+        let mut weights = vec![0.0; bins];
+        // Sum of linear power for an average
+        let mut powers = vec![0.0; if overlap == Overlap::Average { bins } else { 0 }];
+        for (i, hop) in self.hops.iter().enumerate() {
+            let hop_values = self.hop_values(i);
+            let hop_step = if hop.freq_step > 0.0 {
+                hop.freq_step
+            } else {
+                step
+            };
+            let first = ((hop.freq_low as f64 - low) / step - 0.5).ceil().max(0.0) as usize;
+            for bin in first..bins {
+                let center = low + (bin as f64 + 0.5) * step;
+                let position = (center - hop.freq_low as f64) / hop_step;
+                if position >= hop_values.len() as f64 {
+                    break;
+                }
+                let value = hop_values[position.max(0.0) as usize];
+                if value.is_nan() {
+                    continue;
+                }
+                let (slot, weight) = (&mut values[bin], &mut weights[bin]);
+                match overlap {
+                    Overlap::Average => {
+                        powers[bin] += db_to_power(value);
+                        *weight += 1.0;
+                    }
+                    Overlap::Max => {
+                        *slot = if slot.is_nan() {
+                            value
+                        } else {
+                            slot.max(value)
+                        }
+                    }
+                    Overlap::PreferCenter => {
+                        let distance = (position / hop_values.len() as f64 - 0.5).abs();
+                        if slot.is_nan() || distance < *weight {
+                            *slot = value;
+                            *weight = distance;
+                        }
+                    }
+                }
+            }
+        }
+        if overlap == Overlap::Average {
+            for ((value, weight), power) in values.iter_mut().zip(weights).zip(powers) {
+                if weight > 0.0 {
+                    *value = power_to_db(power / weight);
+                }
+            }
+        }
+        Ok(Sweep {
+            date: self.date.clone(),
+            time: self.time.clone(),
+            hops: vec![Hop {
+                freq_low: low.round() as u64,
+                freq_high: high.round() as u64,
+                freq_step: step,
+                start: 0,
+            }],
+            values,
+        })
+    }
+
+    /// Only the bins that overlap `freq_min` to `freq_max`, in Hz
+    pub fn crop(&self, freq_min: f64, freq_max: f64) -> Self {
+        let mut cropped = Sweep {
+            date: self.date.clone(),
+            time: self.time.clone(),
+            hops: Vec::new(),
+            values: Vec::new(),
+        };
+        for (i, hop) in self.hops.iter().enumerate() {
+            let values = self.hop_values(i);
+            let low = hop.freq_low as f64;
+            let (first, end) = if hop.freq_step > 0.0 {
+                (
+                    ((freq_min - low) / hop.freq_step).floor().max(0.0) as usize,
+                    ((freq_max - low) / hop.freq_step).ceil().max(0.0) as usize,
+                )
+            } else if (freq_min..=freq_max).contains(&low) {
+                (0, values.len())
+            } else {
+                (0, 0)
+            };
+            let end = end.min(values.len());
+            if first >= end {
+                continue;
+            }
+            cropped.hops.push(Hop {
+                freq_low: (low + first as f64 * hop.freq_step).round() as u64,
+                freq_high: (low + end as f64 * hop.freq_step).round() as u64,
+                freq_step: hop.freq_step,
+                start: cropped.values.len(),
+            });
+            cropped.values.extend_from_slice(&values[first..end]);
+        }
+        cropped
+    }
+
+    /// Reorders hops by frequency. hackrf_sweep, for example, doesn't write them in order.
+    fn sort_hops(&mut self) {
+        if self.hops.windows(2).all(|w| w[0].freq_low <= w[1].freq_low) {
+            return;
+        }
+        let mut order: Vec<usize> = (0..self.hops.len()).collect();
+        order.sort_by_key(|&i| self.hops[i].freq_low);
+        let mut hops = Vec::with_capacity(self.hops.len());
+        let mut values = Vec::with_capacity(self.values.len());
+        for i in order {
+            let hop = Hop {
+                start: values.len(),
+                ..self.hops[i].clone()
+            };
+            values.extend_from_slice(self.hop_values(i));
+            hops.push(hop);
+        }
+        self.hops = hops;
+        self.values = values;
+    }
+}
+
+/// Whether two sweeps have the same hops, so their bins line up
+pub(crate) fn same_grid(a: &[Hop], b: &[Hop]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.freq_low == b.freq_low
+                && a.freq_high == b.freq_high
+                && a.start == b.start
+                && (a.freq_step - b.freq_step).abs() <= a.freq_step.abs() * 1e-9
+        })
+}
+
+/// Converts a value in dB to linear power
+pub fn db_to_power(db: f32) -> f64 {
+    10f64.powf(db as f64 / 10.0)
+}
+
+/// Converts linear power to dB
+pub fn power_to_db(power: f64) -> f32 {
+    (10.0 * power.log10()) as f32
+}
+
+/// Averages values in dB the way power adds up: converted to linear power, averaged and converted back.
+/// NaN values are skipped, and if there are no others, the average is NaN.
+pub fn mean_power(values: &[f32]) -> f32 {
+    let (sum, count) = values
+        .iter()
+        .filter(|value| !value.is_nan())
+        .fold((0.0, 0usize), |(sum, count), &value| {
+            (sum + db_to_power(value), count + 1)
+        });
+    if count == 0 {
+        return f32::NAN;
+    }
+    power_to_db(sum / count as f64)
+}
+
+/// How to combine the values of hops that cover the same frequency
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overlap {
+    /// Average power of all of them
+    #[default]
+    Average,
+    /// The highest of them
+    Max,
+    /// The value of the hop whose center is the closest, as the edges of a hop are often attenuated
+    PreferCenter,
+}
+
+/// How hops are grouped into sweeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grouping {
+    /// Consecutive hops with the same timestamp form a sweep
+    Timestamp,
+    /// A sweep ends when a hop returns to a frequency it already has, usually its lowest one,
+    /// for tools that timestamp every hop or every transfer, and may write hops out of order
+    Frequency,
+}
+
+/// Collects consecutive hops into sweeps
+#[derive(Debug)]
+pub struct SweepBuilder {
+    grouping: Grouping,
+    current: Option<Sweep>,
+}
+
+impl Default for SweepBuilder {
+    fn default() -> Self {
+        Self::new(Grouping::Timestamp)
+    }
+}
+
+impl SweepBuilder {
+    pub fn new(grouping: Grouping) -> Self {
+        Self {
+            grouping,
+            current: None,
+        }
+    }
+
+    /// Adds a line and its values, returning the previous sweep if this line starts a new one
+    pub fn push(&mut self, line: &Line, values: &[f32]) -> Option<Sweep> {
+        let hop = Hop {
+            freq_low: line.freq_low,
+            freq_high: line.freq_high,
+            freq_step: line.freq_step,
+            start: 0,
+        };
+        self.push_hop(line.date, line.time, hop, values)
+    }
+
+    /// Adds a hop measured at `date` and `time`, returning the previous sweep if this hop starts a new one.
+    /// The `start` of `hop` is ignored.
+    pub fn push_hop(&mut self, date: &str, time: &str, hop: Hop, values: &[f32]) -> Option<Sweep> {
+        let continues = match (&self.current, self.grouping) {
+            (Some(sweep), Grouping::Timestamp) => sweep.date == date && sweep.time == time,
+            (Some(sweep), Grouping::Frequency) => sweep
+                .hops
+                .iter()
+                .all(|other| other.freq_low != hop.freq_low),
+            (None, _) => false,
+        };
+        let finished = if continues {
+            None
+        } else {
+            self.current.replace(Sweep {
+                date: date.to_string(),
+                time: time.to_string(),
+                hops: Vec::new(),
+                values: Vec::new(),
+            })
+        };
+        if let Some(sweep) = self.current.as_mut() {
+            sweep.hops.push(Hop {
+                start: sweep.values.len(),
+                ..hop
+            });
+            sweep.values.extend_from_slice(values);
+        }
+        finished.map(|mut sweep| {
+            sweep.sort_hops();
+            sweep
+        })
+    }
+
+    /// Returns the last sweep
+    pub fn finish(self) -> Option<Sweep> {
+        self.current.map(|mut sweep| {
+            sweep.sort_hops();
+            sweep
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn groups_and_sorts() {
+        let lines = [
+            &b"2019-08-17, 22:37:25, 20, 30, 5, 1, 3.0, 4.0"[..],
+            b"2019-08-17, 22:37:25, 0, 10, 5, 1, 1.0, 2.0",
+            b"2019-08-17, 22:37:35, 0, 10, 5, 1, 5.0, 6.0",
+        ];
+        let mut builder = SweepBuilder::default();
+        let mut sweeps = Vec::new();
+        for raw in lines.iter() {
+            let line = Line::parse(raw).unwrap();
+            let values = line.values().collect::<Result<Vec<_>, _>>().unwrap();
+            sweeps.extend(builder.push(&line, &values));
+        }
+        sweeps.extend(builder.finish());
+
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[0].values, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(sweeps[0].hops[0].freq_low, 0);
+        assert_eq!(sweeps[0].hops[1].start, 2);
+        assert_eq!(sweeps[0].hop_values(1), &[3.0, 4.0]);
+        assert_eq!(sweeps[1].time, "22:37:35");
+        assert_eq!(sweeps[1].width(), 2);
+        assert_eq!(
+            sweeps[1].timestamp(),
+            NaiveDate::from_ymd_opt(2019, 8, 17).and_then(|d| d.and_hms_opt(22, 37, 35))
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let sweep = |time: &str| Sweep {
+            date: "2019-08-17".to_string(),
+            time: time.to_string(),
+            ..Sweep::default()
+        };
+        let timestamp = sweep("22:37:25.250000").timestamp().unwrap();
+        assert_eq!(timestamp.and_utc().timestamp_millis(), 1_566_081_445_250);
+        assert_eq!(sweep("25:00:00").timestamp(), None);
+        assert_eq!(sweep("").timestamp(), None);
+    }
+
+    fn hop(freq_low: u64, freq_step: f64, bins: usize) -> Hop {
+        Hop {
+            freq_low,
+            freq_high: freq_low + (freq_step * bins as f64) as u64,
+            freq_step,
+            start: 0,
+        }
+    }
+
+    fn sweep(hops: &[(Hop, &[f32])]) -> Sweep {
+        let mut builder = SweepBuilder::new(Grouping::Timestamp);
+        for (hop, values) in hops {
+            builder.push_hop("2019-08-17", "22:37:25", hop.clone(), values);
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn averages_power() {
+        assert_eq!(mean_power(&[-20.0]), -20.0);
+        // 1 mW and 0 mW
+        assert!((mean_power(&[0.0, f32::NEG_INFINITY]) - -3.0103).abs() < 1e-4);
+        assert!((mean_power(&[-10.0, f32::NAN, -20.0]) - -12.596).abs() < 1e-3);
+        assert!(mean_power(&[f32::NAN]).is_nan());
+    }
+
+    #[test]
+    fn resamples_overlapping_hops() {
+        let overlapping = sweep(&[
+            (hop(0, 10.0, 4), &[1.0, 1.0, 2.0, 4.0]),
+            (hop(20, 10.0, 4), &[6.0, 8.0, 3.0, 3.0]),
+        ]);
+        assert!(!overlapping.is_linear());
+        let average = overlapping.resample(Overlap::Average).unwrap();
+        let (low, high) = (mean_power(&[2.0, 6.0]), mean_power(&[4.0, 8.0]));
+        assert_eq!(average.values, vec![1.0, 1.0, low, high, 3.0, 3.0]);
+        assert_eq!(average.hops[0].freq_high, 60);
+        let max = overlapping.resample(Overlap::Max).unwrap();
+        assert_eq!(max.values, vec![1.0, 1.0, 6.0, 8.0, 3.0, 3.0]);
+        let center = overlapping.resample(Overlap::PreferCenter).unwrap();
+        assert_eq!(center.values, vec![1.0, 1.0, 2.0, 8.0, 3.0, 3.0]);
+    }
+
+    #[test]
+    fn resamples_gaps_and_steps() {
+        let linear = sweep(&[
+            (hop(0, 10.0, 2), &[1.0, 2.0]),
+            (hop(20, 10.0, 2), &[3.0, 4.0]),
+        ]);
+        assert!(linear.is_linear());
+        assert_eq!(linear.clone().linearize(Overlap::Max).unwrap(), linear);
+
+        let uneven = sweep(&[
+            (hop(0, 10.0, 2), &[1.0, 2.0]),
+            (hop(30, 5.0, 2), &[3.0, 4.0]),
+        ]);
+        let resampled = uneven.linearize(Overlap::Average).unwrap();
+        assert_eq!(resampled.hops[0].freq_step, 5.0);
+        assert_eq!(resampled.values[..4], [1.0, 1.0, 2.0, 2.0]);
+        assert!(resampled.values[4..6].iter().all(|v| v.is_nan()));
+        assert_eq!(resampled.values[6..], [3.0, 4.0]);
+    }
+
+    #[test]
+    fn rejects_huge_grids() {
+        let tiny = sweep(&[
+            (hop(0, 10.0, 2), &[1.0, 2.0]),
+            (hop(30, 0.0000001, 2), &[3.0, 4.0]),
+        ]);
+        assert!(matches!(
+            tiny.resample(Overlap::Average),
+            Err(Error::Geometry { .. })
+        ));
+    }
+
+    #[test]
+    fn crops_frequencies() {
+        let sweep = sweep(&[
+            (hop(0, 10.0, 2), &[1.0, 2.0]),
+            (hop(20, 10.0, 2), &[3.0, 4.0]),
+        ]);
+        let cropped = sweep.crop(15.0, 25.0);
+        assert_eq!(cropped.values, vec![2.0, 3.0]);
+        assert_eq!(cropped.hops[0].freq_low, 10);
+        assert_eq!(cropped.hops[1].freq_high, 30);
+        assert_eq!(cropped.hops[1].start, 1);
+        assert_eq!(sweep.crop(20.0, f64::INFINITY).values, vec![3.0, 4.0]);
+        assert_eq!(sweep.crop(50.0, 60.0).width(), 0);
+    }
+
+    #[test]
+    fn groups_by_frequency() {
+        let mut builder = SweepBuilder::new(Grouping::Frequency);
+        let mut sweeps = Vec::new();
+        // Out of order within a sweep, like hackrf_sweep
+        let hops = [
+            ("1", 0),
+            ("1", 20),
+            ("2", 10),
+            ("3", 0),
+            ("3", 20),
+            ("4", 10),
+        ];
+        for (time, freq_low) in hops.iter() {
+            let hop = Hop {
+                freq_low: *freq_low,
+                freq_high: freq_low + 10,
+                freq_step: 5.0,
+                start: 0,
+            };
+            sweeps.extend(builder.push_hop("2019-08-17", time, hop, &[1.0, 2.0]));
+        }
+        sweeps.extend(builder.finish());
+
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[1].time, "3");
+        assert_eq!(sweeps[1].hops[1].freq_low, 10);
+        assert_eq!(sweeps[1].hops[1].start, 2);
+        assert_eq!(sweeps[1].width(), 6);
+    }
+}
@@ -0,0 +1,37 @@
+//! Helpers for tests that render captures from files.
+
+use crate::formats::InputFormat;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Writes an rtl_power capture named `name` into `dir`, with one sweep a second from `start`,
+/// such as `2019-08-17, 22:37`, each from 0 Hz in `step` Hz bins. Like rtl_power, every line
+/// has a value past its frequency range, which isn't drawn.
+pub(crate) fn capture(
+    dir: &Path,
+    name: &str,
+    start: &str,
+    step: u32,
+    sweeps: &[Vec<f32>],
+) -> PathBuf {
+    let path = dir.join(name);
+    let mut data = String::new();
+    for (second, values) in sweeps.iter().enumerate() {
+        let freq_high = (values.len() as u32 - 1) * step;
+        let values = values
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        data += &format!(
+            "{}:{:02}, 0, {}, {}, 1, {}\n",
+            start, second, freq_high, step, values
+        );
+    }
+    assert_eq!(
+        InputFormat::detect(data.as_bytes()),
+        Some(InputFormat::RtlPower)
+    );
+    fs::write(&path, data).unwrap();
+    path
+}
@@ -0,0 +1,106 @@
+//! Time zones of captures. rtl_power and most other tools write local time without an offset,
+//! so the zone has to be known to tell when a sweep was measured, especially around DST changes.
+
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+pub use chrono_tz::Tz;
+
+/// Where a time range starts or ends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeBound {
+    At(DateTime<Utc>),
+    /// Time since the first sweep of the capture
+    After(Duration),
+}
+
+/// Turns local dates and times, in the order they were written, into instants.
+///
+/// When clocks go back, an hour repeats and its times are ambiguous. They are taken as the first
+/// occurrence, unless that would go back in time, which means the capture already went through it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clock {
+    zone: Tz,
+    last: Option<DateTime<Utc>>,
+}
+
+impl Clock {
+    pub fn new(zone: Tz) -> Self {
+        Self { zone, last: None }
+    }
+
+    pub fn instant(&mut self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let instant = match self.zone.from_local_datetime(&local) {
+            LocalResult::Single(instant) => instant,
+            LocalResult::Ambiguous(earlier, later) => match self.last {
+                Some(last) if earlier < last => later,
+                _ => earlier,
+            },
+            // Clocks went forward past this time, so the tool still used the offset from before
+            LocalResult::None => {
+                self.zone
+                    .from_local_datetime(&(local - Duration::hours(1)))
+                    .earliest()?
+                    + Duration::hours(1)
+            }
+        }
+        .with_timezone(&Utc);
+        self.last = Some(instant);
+        Some(instant)
+    }
+}
+
+/// Formats an instant as RFC 3339 in `zone`, such as `2021-10-31T01:00:00+01:00`, or with `Z` for UTC
+pub fn format_in(instant: DateTime<Utc>, zone: Tz) -> String {
+    instant
+        .with_timezone(&zone)
+        .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn local(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 10, 31)
+            .and_then(|date| date.and_hms_opt(hour, minute, 0))
+            .unwrap()
+    }
+
+    #[test]
+    fn clocks_going_back() {
+        let mut clock = Clock::new(chrono_tz::Europe::London);
+        let instants = [(0, 30), (1, 0), (1, 30), (1, 0), (1, 30), (2, 0)]
+            .iter()
+            .map(|&(hour, minute)| clock.instant(local(hour, minute)).unwrap())
+            .map(|instant| format_in(instant, Tz::UTC))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            instants,
+            [
+                "2021-10-30T23:30:00Z",
+                "2021-10-31T00:00:00Z",
+                "2021-10-31T00:30:00Z",
+                "2021-10-31T01:00:00Z",
+                "2021-10-31T01:30:00Z",
+                "2021-10-31T02:00:00Z",
+            ]
+        );
+    }
+
+    #[test]
+    fn clocks_going_forward() {
+        let mut clock = Clock::new(chrono_tz::Europe::London);
+        let spring = |hour, minute| {
+            NaiveDate::from_ymd_opt(2021, 3, 28)
+                .and_then(|date| date.and_hms_opt(hour, minute, 0))
+                .unwrap()
+        };
+        let missing = clock.instant(spring(1, 30)).unwrap();
+        assert_eq!(format_in(missing, Tz::UTC), "2021-03-28T01:30:00Z");
+        let after = clock.instant(spring(2, 0)).unwrap();
+        assert_eq!(
+            format_in(after, chrono_tz::Europe::London),
+            "2021-03-28T02:00:00+01:00"
+        );
+    }
+}
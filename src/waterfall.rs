@@ -0,0 +1,771 @@
+//! The values of a capture laid out like the image, one row per sweep, before they are colored.
+
+use crate::error::Result;
+use crate::formats::{self, head};
+use crate::lenient::{Mismatch, ReadOptions, Report, WidthPolicy};
+use crate::palettes::{ColorScale, Palette};
+use crate::sigmf::Extent;
+use crate::sweep::{mean_power, Sweep};
+use crate::timezone::{format_in, Clock, Tz};
+use chrono::{DateTime, Utc};
+use log::*;
+use rayon::prelude::*;
+use std::{collections::HashMap, io::Read};
+
+/// Placing rows in time gives up if the rows it would add have more values than this, 512 MiB,
+/// which is usually a wrong timestamp
+const MAX_GAP_VALUES: usize = 1 << 27;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Waterfall {
+    pub width: usize,
+    /// Values of all rows, one after another
+    pub values: Vec<f32>,
+    /// When every row was measured, `None` for rows added for gaps and for sweeps without a readable timestamp
+    pub times: Vec<Option<DateTime<Utc>>>,
+    /// Rows where another capture starts, when several were appended
+    pub boundaries: Vec<usize>,
+}
+
+impl Waterfall {
+    pub fn height(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn row(&self, index: usize) -> &[f32] {
+        &self.values[index * self.width..(index + 1) * self.width]
+    }
+
+    /// Adds the rows of `other` after these
+    pub fn append(&mut self, other: Self) {
+        if self.times.is_empty() {
+            self.width = other.width;
+        }
+        let offset = self.height();
+        self.values.extend(other.values);
+        self.times.extend(other.times);
+        self.boundaries
+            .extend(other.boundaries.into_iter().map(|row| row + offset));
+    }
+
+    /// Reads all sweeps of a capture
+    pub fn read<R: Read>(file: R, options: &ReadOptions) -> Result<(Self, Report)> {
+        let (waterfall, _, report) = Self::read_with_extent(file, options)?;
+        Ok((waterfall, report))
+    }
+
+    /// Same as [`Waterfall::read`], but also returns the extent of the sweeps that were kept,
+    /// so a compressed capture only has to be decompressed once
+    pub(crate) fn read_with_extent<R: Read>(
+        file: R,
+        options: &ReadOptions,
+    ) -> Result<(Self, Extent, Report)> {
+        let mut rows = Rows::default();
+        let mut extent = Extent::default();
+        let mut report = Report::default();
+        let (options, reader) = formats::read_start(file, options)?;
+        let options = &options;
+        // Every sweep has to be localized in order, even those outside the selected times,
+        // to tell which repeated hour a time is in when clocks go back
+        let mut clock = Clock::new(options.timezone());
+        formats::for_each_sweep(reader, &options.all_times(), &mut report, |sweep| {
+            let time = sweep.timestamp().and_then(|time| clock.instant(time));
+            if options.selects(time) {
+                rows.push_at(&sweep, time);
+                extent.add(&sweep, time);
+            }
+            Ok(())
+        })?;
+        let waterfall = rows.into_waterfall(options, &mut report);
+        info!("Img data {}x{}", waterfall.width, waterfall.height());
+        Ok((waterfall, extent, report))
+    }
+
+    /// Collects sweeps that were read some other way, checking their widths the same way as [`Waterfall::read`]
+    pub(crate) fn from_sweeps<I: IntoIterator<Item = Sweep>>(
+        sweeps: I,
+        options: &ReadOptions,
+        report: &mut Report,
+    ) -> Self {
+        let mut rows = Rows::default();
+        for sweep in sweeps {
+            rows.push(&sweep);
+        }
+        rows.localize(options);
+        rows.into_waterfall(options, report)
+    }
+
+    /// Same as [`Waterfall::read`], but works on an in-memory buffer in parallel
+    pub fn read_slice(data: &[u8], options: &ReadOptions) -> Result<(Self, Report)> {
+        let options = &options.for_input(head(data));
+        let parts = options
+            .format()
+            .split(data, rayon::current_num_threads())
+            .into_par_iter()
+            .map(|chunk| {
+                let mut rows = Rows::default();
+                let mut report = Report::default();
+                formats::for_each_sweep_in(data, chunk, options, &mut report, |sweep| {
+                    rows.push(&sweep);
+                    Ok(())
+                })
+                .map(|_| (rows, report))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (mut rows, mut report) = parts.into_iter().fold(
+            (Rows::default(), Report::default()),
+            |(mut rows, report), part| {
+                rows.append(part.0);
+                (rows, Report::merge(report, part.1))
+            },
+        );
+        rows.localize(options);
+        let waterfall = rows.into_waterfall(options, &mut report);
+        info!("Img data {}x{}", waterfall.width, waterfall.height());
+        Ok((waterfall, report))
+    }
+
+    /// Typical time between two sweeps: the median of the steps forward in time
+    pub fn interval(&self) -> Option<chrono::Duration> {
+        let mut steps = self
+            .times
+            .iter()
+            .flatten()
+            .zip(self.times.iter().flatten().skip(1))
+            .map(|(a, b)| *b - *a)
+            .filter(|step| *step > chrono::Duration::zero())
+            .collect::<Vec<_>>();
+        if steps.is_empty() {
+            return None;
+        }
+        let middle = (steps.len() - 1) / 2;
+        Some(*steps.select_nth_unstable(middle).1)
+    }
+
+    /// Places rows on a uniform time axis, adding rows of NaN where sweeps are missing,
+    /// such as when rtl_power was restarted. Returns how many rows were added.
+    pub fn place_in_time(&mut self) -> usize {
+        let interval = match self.interval() {
+            Some(interval) => interval,
+            None => return 0,
+        };
+        let mut previous = None;
+        let gaps = self
+            .times
+            .iter()
+            .map(|time| {
+                let gap = match (previous, time) {
+                    (Some(previous), Some(time)) if *time > previous => {
+                        let steps = (*time - previous).num_microseconds().map_or(f64::MAX, |t| {
+                            t as f64 / interval.num_microseconds().unwrap_or(1) as f64
+                        });
+                        (steps.round() as usize).saturating_sub(1)
+                    }
+                    _ => 0,
+                };
+                previous = time.or(previous);
+                gap
+            })
+            .collect::<Vec<_>>();
+        let added = gaps
+            .iter()
+            .fold(0usize, |sum, gap| sum.saturating_add(*gap));
+        if added == 0 {
+            return 0;
+        }
+        if added.saturating_mul(self.width.max(1)) > MAX_GAP_VALUES {
+            warn!(
+                "Not placing sweeps in time, gaps would add {} rows of {} bins. Are the timestamps right?",
+                added, self.width
+            );
+            return 0;
+        }
+        info!(
+            "Adding {} empty rows for gaps longer than the {}s between sweeps",
+            added,
+            interval.num_milliseconds() as f64 / 1000.0
+        );
+        let mut values = Vec::with_capacity(self.values.len() + added * self.width);
+        let mut times = Vec::with_capacity(self.times.len() + added);
+        let mut rows = Vec::with_capacity(self.times.len());
+        for (index, gap) in gaps.into_iter().enumerate() {
+            values.resize(values.len() + gap * self.width, f32::NAN);
+            times.resize(times.len() + gap, None);
+            rows.push(times.len());
+            values.extend_from_slice(self.row(index));
+            times.push(self.times[index]);
+        }
+        for boundary in self.boundaries.iter_mut() {
+            *boundary = rows[*boundary];
+        }
+        self.values = values;
+        self.times = times;
+        added
+    }
+
+    /// Combines blocks of neighbouring bins and rows into one value, so the result is at most `width` by `height`.
+    /// Sizes that aren't set or are larger stay the same.
+    pub fn downsample(
+        self,
+        width: Option<usize>,
+        height: Option<usize>,
+        aggregation: Aggregation,
+    ) -> Self {
+        let fit =
+            |target: Option<usize>, size: usize| target.map_or(size, |t| t.clamp(1, size.max(1)));
+        let (new_width, new_height) = (fit(width, self.width), fit(height, self.height()));
+        if (new_width, new_height) == (self.width, self.height()) || self.values.is_empty() {
+            return self;
+        }
+        info!(
+            "Downsampling {}x{} to {}x{}",
+            self.width,
+            self.height(),
+            new_width,
+            new_height
+        );
+        let block = |index: usize, size: usize, new_size: usize| {
+            (index * size / new_size, (index + 1) * size / new_size)
+        };
+        let values = (0..new_height)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let (top, bottom) = block(i, self.height(), new_height);
+                let mut scratch = Vec::new();
+                (0..new_width)
+                    .map(|j| {
+                        let (left, right) = block(j, self.width, new_width);
+                        scratch.clear();
+                        for row in top..bottom {
+                            scratch.extend_from_slice(&self.row(row)[left..right]);
+                        }
+                        aggregation.apply(&mut scratch)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let times = (0..new_height)
+            .map(|i| {
+                let (top, bottom) = block(i, self.height(), new_height);
+                self.times[top..bottom].iter().flatten().next().copied()
+            })
+            .collect();
+        let mut boundaries = self
+            .boundaries
+            .iter()
+            .map(|row| row * new_height / self.height())
+            .collect::<Vec<_>>();
+        boundaries.dedup();
+        Waterfall {
+            width: new_width,
+            values,
+            times,
+            boundaries,
+        }
+    }
+
+    /// Repeats or interpolates values, so each of them covers `scale.x` by `scale.y` pixels
+    pub fn upsample(self, scale: &Scale) -> Self {
+        let (x, y) = (scale.x.max(1), scale.y.max(1));
+        if (x, y) == (1, 1) || self.values.is_empty() {
+            return self;
+        }
+        let (new_width, new_height) = (self.width * x, self.height() * y);
+        info!(
+            "Upsampling {}x{} to {}x{}",
+            self.width,
+            self.height(),
+            new_width,
+            new_height
+        );
+        let interpolation = scale.interpolation;
+        let columns = (0..new_width)
+            .map(|j| interpolation.source(j, x, self.width))
+            .collect::<Vec<_>>();
+        let values = (0..new_height)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let (top, bottom, down) = interpolation.source(i, y, self.height());
+                let (top, bottom) = (self.row(top), self.row(bottom));
+                columns.iter().map(move |&(left, right, across)| {
+                    lerp(
+                        lerp(top[left], top[right], across),
+                        lerp(bottom[left], bottom[right], across),
+                        down,
+                    )
+                })
+            })
+            .collect();
+        let times = (0..new_height).map(|i| self.times[i / y]).collect();
+        let boundaries = self.boundaries.iter().map(|row| row * y).collect();
+        Waterfall {
+            width: new_width,
+            values,
+            times,
+            boundaries,
+        }
+    }
+
+    /// Median of each bin over time, ignoring NaN, to estimate the noise floor and fixed spurs
+    pub fn baseline(&self) -> Vec<f32> {
+        (0..self.width)
+            .into_par_iter()
+            .map(|column| {
+                let mut values = self
+                    .values
+                    .iter()
+                    .skip(column)
+                    .step_by(self.width)
+                    .copied()
+                    .collect();
+                Aggregation::Percentile(50.0).apply(&mut values)
+            })
+            .collect()
+    }
+
+    /// Subtracts `baseline` from every row, so values are relative to it
+    pub fn subtract(&mut self, baseline: &[f32]) {
+        self.values
+            .par_chunks_mut(self.width.max(1))
+            .for_each(|row| {
+                for (value, base) in row.iter_mut().zip(baseline) {
+                    *value -= base;
+                }
+            });
+    }
+
+    /// Subtracts the level of each row, combined from its values by `level`, so rows are relative to themselves
+    pub fn level_rows(&mut self, level: Aggregation) {
+        self.values
+            .par_chunks_mut(self.width.max(1))
+            .for_each(|row| {
+                let level = level.apply(&mut row.to_vec());
+                for value in row.iter_mut() {
+                    *value -= level;
+                }
+            });
+    }
+
+    /// Colors every value, NaN included, the same way [`scale_tocolor`] does
+    pub fn color(&self, palette: Palette, min: f32, max: f32) -> Vec<u8> {
+        self.color_with(palette, &ColorScale::linear(min, max))
+    }
+
+    /// Colors every value, through the transfer function of `scale`
+    pub fn color_with(&self, palette: Palette, scale: &ColorScale) -> Vec<u8> {
+        self.values
+            .par_chunks(self.width.max(1))
+            .flat_map_iter(|row| {
+                row.iter()
+                    .flat_map(move |&value| scale.color(palette, value))
+            })
+            .collect()
+    }
+}
+
+/// How to combine the values that end up in one pixel
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Aggregation {
+    /// Average power, computed in linear power rather than dB
+    #[default]
+    Mean,
+    /// Arithmetic average of the values as they are, for values that aren't power, such as differences in dB
+    Average,
+    /// The strongest, so short bursts don't disappear
+    Max,
+    Min,
+    /// The value this percentage of values are below, such as 50 for the median
+    Percentile(f32),
+}
+
+impl Aggregation {
+    /// Combines `values`, ignoring NaN, or returns NaN if there are no others. Reorders `values`.
+    pub fn apply(self, values: &mut Vec<f32>) -> f32 {
+        values.retain(|value| !value.is_nan());
+        if values.is_empty() {
+            return f32::NAN;
+        }
+        match self {
+            Aggregation::Mean => mean_power(values),
+            Aggregation::Average => values.iter().sum::<f32>() / values.len() as f32,
+            Aggregation::Max => values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            Aggregation::Min => values.iter().copied().fold(f32::INFINITY, f32::min),
+            Aggregation::Percentile(percentile) => {
+                let rank =
+                    (percentile.clamp(0.0, 100.0) / 100.0 * (values.len() - 1) as f32).round();
+                *values
+                    .select_nth_unstable_by(rank as usize, |a, b| a.total_cmp(b))
+                    .1
+            }
+        }
+    }
+}
+
+/// How many pixels each value covers when upsampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    pub x: usize,
+    pub y: usize,
+    pub interpolation: Interpolation,
+}
+
+/// How to fill the pixels between values when upsampling
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Interpolation {
+    /// Repeat each value, so bins stay sharp
+    #[default]
+    Nearest,
+    /// Blend neighbouring values, which smooths the edges between bins
+    Linear,
+}
+
+impl Interpolation {
+    /// The two values output pixel `index` lies between, and how far it is from the first one
+    fn source(self, index: usize, scale: usize, size: usize) -> (usize, usize, f32) {
+        match self {
+            Interpolation::Nearest => (index / scale, index / scale, 0.0),
+            Interpolation::Linear => {
+                let position =
+                    ((index as f32 + 0.5) / scale as f32 - 0.5).clamp(0.0, (size - 1) as f32);
+                let first = position as usize;
+                (first, (first + 1).min(size - 1), position - first as f32)
+            }
+        }
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    if t == 0.0 {
+        a
+    } else {
+        a + (b - a) * t
+    }
+}
+
+/// Sweeps as they were read, before their widths are checked
+#[derive(Debug, Default)]
+struct Rows {
+    values: Vec<f32>,
+    widths: Vec<usize>,
+    times: Vec<Option<DateTime<Utc>>>,
+}
+
+impl Rows {
+    /// Adds a sweep, taking its timestamp as UTC until the rows are localized
+    fn push(&mut self, sweep: &Sweep) {
+        self.push_at(sweep, sweep.timestamp().map(|time| time.and_utc()));
+    }
+
+    /// Adds a sweep measured at `time`
+    fn push_at(&mut self, sweep: &Sweep, time: Option<DateTime<Utc>>) {
+        self.push_values(&sweep.values, time);
+    }
+
+    fn push_values(&mut self, values: &[f32], time: Option<DateTime<Utc>>) {
+        self.values.extend_from_slice(values);
+        self.widths.push(values.len());
+        self.times.push(time);
+    }
+
+    fn append(&mut self, other: Self) {
+        self.values.extend(other.values);
+        self.widths.extend(other.widths);
+        self.times.extend(other.times);
+    }
+
+    /// Takes the timestamps, which were read as UTC, as local times of the input instead,
+    /// and leaves out rows outside the selected times.
+    /// This has to see all rows in order, to tell which repeated hour a time is in when clocks go back.
+    fn localize(&mut self, options: &ReadOptions) {
+        let zone = options.timezone();
+        if zone != Tz::UTC {
+            let mut clock = Clock::new(zone);
+            for time in self.times.iter_mut() {
+                *time = time.and_then(|time| clock.instant(time.naive_utc()));
+            }
+        }
+        if options.start.is_none() && options.end.is_none() {
+            return;
+        }
+        let rows = std::mem::take(self);
+        let mut start = 0;
+        for (width, time) in rows.widths.into_iter().zip(rows.times) {
+            let values = &rows.values[start..start + width];
+            start += width;
+            if options.selects(time) {
+                self.push_values(values, time);
+            }
+        }
+    }
+
+    /// The most common width, so a partial first sweep doesn't decide it
+    fn width(&self) -> usize {
+        let mut counts = HashMap::new();
+        for &width in self.widths.iter() {
+            *counts.entry(width).or_insert(0) += 1;
+        }
+        self.widths
+            .iter()
+            .copied()
+            .max_by_key(|width| (counts[width], std::cmp::Reverse(*width)))
+            .unwrap_or(0)
+    }
+
+    /// Lays the rows out with the most common width, padding or dropping the others as `options` say
+    fn into_waterfall(self, options: &ReadOptions, report: &mut Report) -> Waterfall {
+        let zone = options.timezone();
+        let width = self.width();
+        let mut waterfall = Waterfall {
+            width,
+            values: Vec::with_capacity(width * self.widths.len()),
+            ..Waterfall::default()
+        };
+        let mut start = 0;
+        for (row, (row_width, time)) in self.widths.into_iter().zip(self.times).enumerate() {
+            let values = &self.values[start..start + row_width];
+            start += row_width;
+            if row_width != width {
+                let timestamp = time.map_or_else(
+                    || format!("sweep {}", row + 1),
+                    |time| format_in(time, zone),
+                );
+                report.mismatch(
+                    Mismatch {
+                        timestamp,
+                        width: row_width,
+                        expected: width,
+                    },
+                    options.mismatch,
+                );
+                if options.mismatch == WidthPolicy::Drop {
+                    continue;
+                }
+            }
+            waterfall
+                .values
+                .extend_from_slice(&values[..row_width.min(width)]);
+            waterfall.times.push(time);
+            waterfall
+                .values
+                .resize(waterfall.times.len() * width, f32::NAN);
+        }
+        waterfall
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenient::Problem;
+    use crate::timezone::TimeBound;
+    use chrono::TimeZone;
+
+    fn waterfall(seconds: &[u32]) -> Waterfall {
+        let mut rows = Rows::default();
+        for (i, second) in seconds.iter().enumerate() {
+            rows.push(&Sweep {
+                date: "2019-08-17".to_string(),
+                time: format!("22:{:02}:{:02}", second / 60, second % 60),
+                values: vec![i as f32; 2],
+                ..Sweep::default()
+            });
+        }
+        rows.into_waterfall(&ReadOptions::default(), &mut Report::default())
+    }
+
+    #[test]
+    fn interval() {
+        assert_eq!(
+            waterfall(&[0, 10, 21, 30, 60]).interval(),
+            Some(chrono::Duration::seconds(10))
+        );
+        assert_eq!(waterfall(&[0]).interval(), None);
+    }
+
+    #[test]
+    fn places_rows_in_time() {
+        let mut waterfall = waterfall(&[0, 10, 21, 30, 60, 70]);
+        waterfall.boundaries.push(4);
+        assert_eq!(waterfall.place_in_time(), 2);
+        assert_eq!(waterfall.height(), 8);
+        assert_eq!(waterfall.row(3), &[3.0, 3.0]);
+        assert!(waterfall.row(4).iter().all(|v| v.is_nan()));
+        assert!(waterfall.row(5).iter().all(|v| v.is_nan()));
+        assert_eq!(waterfall.times[5], None);
+        assert_eq!(waterfall.row(6), &[4.0, 4.0]);
+        assert_eq!(waterfall.boundaries, vec![6]);
+    }
+
+    #[test]
+    fn clocks_going_back_leave_no_gap() {
+        // 01:59:40 to 01:00:20 local time, as clocks in London go back an hour at 02:00
+        let data = b"2021-10-31, 01:59:40, 0, 2, 1, 1, 1.0, 1.0, 1.0
+2021-10-31, 01:59:50, 0, 2, 1, 1, 1.0, 1.0, 1.0
+2021-10-31, 01:00:00, 0, 2, 1, 1, 1.0, 1.0, 1.0
+2021-10-31, 01:00:20, 0, 2, 1, 1, 1.0, 1.0, 1.0
+";
+        let options = ReadOptions {
+            timezone: chrono_tz::Europe::London,
+            ..ReadOptions::default()
+        };
+        let (mut waterfall, _) = Waterfall::read(&data[..], &options).unwrap();
+        assert_eq!(waterfall, Waterfall::read_slice(data, &options).unwrap().0);
+        assert_eq!(waterfall.place_in_time(), 1);
+        assert_eq!(waterfall.height(), 5);
+        assert_eq!(waterfall.times[3], None);
+    }
+
+    #[test]
+    fn skips_huge_gaps() {
+        let start = Utc.with_ymd_and_hms(2019, 8, 17, 22, 37, 0).unwrap();
+        let seconds = |s: i64| Some(start + chrono::Duration::seconds(s));
+        let mut waterfall = Waterfall {
+            width: 4,
+            values: vec![0.0; 16],
+            times: vec![seconds(0), seconds(10), seconds(20), seconds(10 << 26)],
+            boundaries: Vec::new(),
+        };
+        assert_eq!(waterfall.place_in_time(), 0);
+        assert_eq!(waterfall.height(), 4);
+    }
+
+    #[test]
+    fn selects_times_across_chunks() {
+        // Clocks in London go back an hour at 02:00, so 01:00 to 01:59 repeats
+        let data = b"2021-10-31, 01:20:00, 0, 2, 1, 1, 1.0, 1.0, 1.0
+2021-10-31, 01:40:00, 0, 2, 1, 1, 2.0, 2.0, 2.0
+2021-10-31, 01:00:00, 0, 2, 1, 1, 3.0, 3.0, 3.0
+2021-10-31, 01:20:00, 0, 2, 1, 1, 4.0, 4.0, 4.0
+2021-10-31, 01:40:00, 0, 2, 1, 1, 5.0, 5.0, 5.0
+2021-10-31, 02:00:00, 0, 2, 1, 1, 6.0, 6.0, 6.0
+";
+        let start = Utc.with_ymd_and_hms(2021, 10, 31, 1, 10, 0).unwrap();
+        let options = ReadOptions {
+            timezone: chrono_tz::Europe::London,
+            start: Some(TimeBound::At(start)),
+            ..ReadOptions::default()
+        };
+        let (waterfall, _) = Waterfall::read(&data[..], &options).unwrap();
+        assert_eq!(waterfall.height(), 3);
+        assert_eq!(waterfall.row(0), &[4.0, 4.0]);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(6)
+            .build()
+            .unwrap();
+        let (in_chunks, _) = pool
+            .install(|| Waterfall::read_slice(data, &options))
+            .unwrap();
+        assert_eq!(in_chunks, waterfall);
+    }
+
+    #[test]
+    fn keeps_rows_going_back_in_time() {
+        let mut waterfall = waterfall(&[0, 10, 20, 5, 15]);
+        assert_eq!(waterfall.place_in_time(), 0);
+        assert_eq!(waterfall.height(), 5);
+    }
+
+    #[test]
+    fn width_mismatches() {
+        let data = b"2019-08-17, 22:37:25, 0, 2, 1, 1, 1.0, 1.0, 1.0
+2019-08-17, 22:37:35, 0, 2, 1, 1, 2.0, 2.0, 2.0
+2019-08-17, 22:37:45, 0, 1, 1, 1, 3.0, 3.0
+2019-08-17, 22:37:55, 0, 2, 1, 1, 4.0, 4.0, 4.0
+";
+        let (padded, report) = Waterfall::read(&data[..], &ReadOptions::default()).unwrap();
+        assert_eq!(padded.height(), 4);
+        assert_eq!(padded.row(2)[0], 3.0);
+        assert!(padded.row(2)[1].is_nan());
+        assert_eq!(
+            report.mismatched,
+            vec![Mismatch {
+                timestamp: "2019-08-17T22:37:45Z".to_string(),
+                width: 1,
+                expected: 2,
+            }]
+        );
+        assert_eq!(report.repaired.get(&Problem::WidthMismatch), Some(&1));
+
+        let options = ReadOptions {
+            mismatch: WidthPolicy::Drop,
+            ..ReadOptions::default()
+        };
+        let (dropped, report) = Waterfall::read_slice(data, &options).unwrap();
+        assert_eq!(dropped.values, vec![1.0, 1.0, 2.0, 2.0, 4.0, 4.0]);
+        assert_eq!(report.dropped.get(&Problem::WidthMismatch), Some(&1));
+    }
+
+    #[test]
+    fn aggregations() {
+        let values = [-10.0, -20.0, f32::NAN, -30.0, -40.0];
+        let apply = |aggregation: Aggregation| aggregation.apply(&mut values.to_vec());
+        assert_eq!(apply(Aggregation::Max), -10.0);
+        assert_eq!(apply(Aggregation::Min), -40.0);
+        assert_eq!(apply(Aggregation::Percentile(50.0)), -20.0);
+        assert_eq!(apply(Aggregation::Percentile(100.0)), -10.0);
+        // 0.1 + 0.01 + 0.001 + 0.0001 mW on average
+        assert!((apply(Aggregation::Mean) - -15.563).abs() < 1e-3);
+        assert_eq!(apply(Aggregation::Average), -25.0);
+        assert!(Aggregation::Max.apply(&mut vec![f32::NAN]).is_nan());
+    }
+
+    #[test]
+    fn downsamples() {
+        let mut tall = waterfall(&[0, 10, 20, 30, 40]);
+        tall.boundaries.push(3);
+        let third = tall.times[2];
+        let downsampled = tall.downsample(Some(1), Some(2), Aggregation::Max);
+        assert_eq!(downsampled.width, 1);
+        assert_eq!(downsampled.values, vec![1.0, 4.0]);
+        assert_eq!(downsampled.boundaries, vec![1]);
+        assert_eq!(downsampled.times[1], third);
+
+        let same = waterfall(&[0, 10]).downsample(Some(5), None, Aggregation::Max);
+        assert_eq!(same, waterfall(&[0, 10]));
+    }
+
+    #[test]
+    fn upsamples() {
+        let mut short = waterfall(&[0, 10]);
+        short.boundaries.push(1);
+        let scale = |interpolation| Scale {
+            x: 2,
+            y: 2,
+            interpolation,
+        };
+        let nearest = short.clone().upsample(&scale(Interpolation::Nearest));
+        assert_eq!((nearest.width, nearest.height()), (4, 4));
+        assert_eq!(nearest.row(1), &[0.0; 4]);
+        assert_eq!(nearest.row(2), &[1.0; 4]);
+        assert_eq!(nearest.boundaries, vec![2]);
+        assert_eq!(nearest.times[3], short.times[1]);
+
+        let linear = short.upsample(&scale(Interpolation::Linear));
+        let column = (0..4).map(|i| linear.row(i)[0]).collect::<Vec<_>>();
+        assert_eq!(column, vec![0.0, 0.25, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn subtracts_baseline() {
+        let mut flat = waterfall(&[0, 10, 20, 30]);
+        flat.values[1] = f32::NAN;
+        let baseline = flat.baseline();
+        assert_eq!(baseline, vec![2.0, 2.0]);
+        flat.subtract(&baseline);
+        assert_eq!(flat.row(0)[0], -2.0);
+        assert!(flat.row(0)[1].is_nan());
+        assert_eq!(flat.row(3), &[1.0, 1.0]);
+    }
+
+    #[test]
+    fn levels_rows() {
+        let mut rows = waterfall(&[0, 10]);
+        rows.values = vec![1.0, 3.0, 5.0, 10.0, f32::NAN, 20.0];
+        rows.width = 3;
+        rows.times.truncate(2);
+        rows.level_rows(Aggregation::Percentile(50.0));
+        assert_eq!(rows.row(0), &[-2.0, 0.0, 2.0]);
+        assert_eq!(rows.row(1)[2], 0.0);
+        assert!(rows.row(1)[1].is_nan());
+    }
+}